@@ -0,0 +1,236 @@
+use crate::memory::{Memory, CELL_SIZE};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// this file is responsible for the block word set, 1024 byte blocks kept in a file and
+// cached in buffers that live in the heap, so forth code can use them like any other memory
+
+pub(crate) const BLOCK_SIZE: usize = 1024;
+// blocks are shown and loaded as this many lines of this many characters
+pub(crate) const BLOCK_LINES: usize = 16;
+pub(crate) const BLOCK_LINE_LENGTH: usize = 64;
+// how many blocks can be in memory at once
+const BLOCK_BUFFERS: usize = 4;
+// the highest block number, so a mistyped one can't make a block file of terabytes
+const MAX_BLOCK: i64 = 65536;
+
+pub(crate) const DEFAULT_BLOCK_FILE: &str = "blocks.blk";
+
+#[derive(Debug, Clone)]
+struct BlockBuffer {
+    addr: i64,
+    // the block number in this buffer, if any
+    block: Option<i64>,
+    dirty: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct BlockStore {
+    path: PathBuf,
+    // made the first time a block is used, so programs that never use blocks don't pay for them
+    buffers: Vec<BlockBuffer>,
+    // index of the buffer update marks, the one most recently returned by block or buffer
+    current: Option<usize>,
+    // the buffer to reuse next when every buffer holds a block
+    next_victim: usize,
+    // address of scr, allocated on first use
+    scr: Option<i64>,
+}
+
+fn invalid_block_err(block: i64) -> String {
+    format!("Invalid block number {block}")
+}
+
+// where a block starts in the file, block numbers having already been checked
+fn block_offset(block: i64) -> Result<u64, String> {
+    (block as u64 - 1)
+        .checked_mul(BLOCK_SIZE as u64)
+        .ok_or_else(|| invalid_block_err(block))
+}
+
+fn block_file_err(path: &Path, err: std::io::Error) -> String {
+    format!("Block file {}: {err}", path.display())
+}
+
+impl BlockStore {
+    pub(crate) fn new(path: PathBuf) -> BlockStore {
+        BlockStore {
+            path,
+            buffers: Vec::with_capacity(BLOCK_BUFFERS),
+            current: None,
+            next_victim: 0,
+            scr: None,
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn scr(&mut self, memory: &mut Memory) -> Result<i64, String> {
+        match self.scr {
+            Some(addr) => Ok(addr),
+            None => {
+                let addr = memory.allocate(CELL_SIZE).ok_or("Could not allocate scr")?;
+                self.scr = Some(addr);
+                Ok(addr)
+            }
+        }
+    }
+
+    // the address of a buffer holding `block`, reading it from the file if it isn't already in one
+    pub(crate) fn block(&mut self, memory: &mut Memory, block: i64) -> Result<i64, String> {
+        self.assign(memory, block, true)
+    }
+
+    // like block, but the contents are unspecified so the file isn't read
+    pub(crate) fn buffer(&mut self, memory: &mut Memory, block: i64) -> Result<i64, String> {
+        self.assign(memory, block, false)
+    }
+
+    pub(crate) fn update(&mut self) -> Result<(), String> {
+        match self.current {
+            Some(index) => {
+                self.buffers[index].dirty = true;
+                Ok(())
+            }
+            None => Err("No current block to update".to_string()),
+        }
+    }
+
+    // write every changed buffer back to the file
+    pub(crate) fn save_buffers(&mut self, memory: &Memory) -> Result<(), String> {
+        for index in 0..self.buffers.len() {
+            self.write_back(memory, index)?;
+        }
+        Ok(())
+    }
+
+    // save, then forget what every buffer holds
+    pub(crate) fn flush(&mut self, memory: &Memory) -> Result<(), String> {
+        self.save_buffers(memory)?;
+        for buffer in self.buffers.iter_mut() {
+            buffer.block = None;
+        }
+        self.current = None;
+        Ok(())
+    }
+
+    fn assign(&mut self, memory: &mut Memory, block: i64, read: bool) -> Result<i64, String> {
+        if !(1..=MAX_BLOCK).contains(&block) {
+            return Err(invalid_block_err(block));
+        }
+
+        let index = match self.buffers.iter().position(|b| b.block == Some(block)) {
+            Some(index) => index,
+            None => {
+                let index = self.free_buffer(memory)?;
+                let addr = self.buffers[index].addr;
+                let contents = memory.bytes_mut(addr, BLOCK_SIZE)?;
+                if read {
+                    read_block(&self.path, block, contents)?;
+                } else {
+                    contents.fill(b' ');
+                }
+                self.buffers[index].block = Some(block);
+                self.buffers[index].dirty = false;
+                index
+            }
+        };
+
+        self.current = Some(index);
+        Ok(self.buffers[index].addr)
+    }
+
+    // a buffer not holding a block, writing one back to make room if needed
+    fn free_buffer(&mut self, memory: &mut Memory) -> Result<usize, String> {
+        if let Some(index) = self.buffers.iter().position(|b| b.block.is_none()) {
+            return Ok(index);
+        }
+        if self.buffers.len() < BLOCK_BUFFERS {
+            let addr = memory
+                .allocate(BLOCK_SIZE as i64)
+                .ok_or("Could not allocate a block buffer")?;
+            self.buffers.push(BlockBuffer {
+                addr,
+                block: None,
+                dirty: false,
+            });
+            return Ok(self.buffers.len() - 1);
+        }
+
+        let index = self.next_victim;
+        self.next_victim = (self.next_victim + 1) % BLOCK_BUFFERS;
+        self.write_back(memory, index)?;
+        self.buffers[index].block = None;
+        Ok(index)
+    }
+
+    fn write_back(&mut self, memory: &Memory, index: usize) -> Result<(), String> {
+        let buffer = &self.buffers[index];
+        if let (Some(block), true) = (buffer.block, buffer.dirty) {
+            let contents = memory.bytes(buffer.addr, BLOCK_SIZE)?;
+            write_block(&self.path, block, contents)?;
+            self.buffers[index].dirty = false;
+        }
+        Ok(())
+    }
+}
+
+// blocks past the end of the file read as blank
+fn read_block(path: &Path, block: i64, contents: &mut [u8]) -> Result<(), String> {
+    contents.fill(b' ');
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(block_file_err(path, err)),
+    };
+
+    let offset = block_offset(block)?;
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|err| block_file_err(path, err))?;
+    let mut read = 0;
+    while read < contents.len() {
+        match file.read(&mut contents[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) => return Err(block_file_err(path, err)),
+        }
+    }
+    Ok(())
+}
+
+fn write_block(path: &Path, block: i64, contents: &[u8]) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|err| block_file_err(path, err))?;
+
+    // seeking past the end leaves a hole, fill it with blank blocks instead
+    let len = file
+        .metadata()
+        .map_err(|err| block_file_err(path, err))?
+        .len();
+    let offset = block_offset(block)?;
+    if len < offset {
+        file.seek(SeekFrom::End(0))
+            .map_err(|err| block_file_err(path, err))?;
+        // a block's worth at a time, the gap can be far bigger than is worth holding in memory
+        let padding = [b' '; BLOCK_SIZE];
+        let mut remaining = offset - len;
+        while remaining > 0 {
+            let chunk = remaining.min(BLOCK_SIZE as u64) as usize;
+            file.write_all(&padding[..chunk])
+                .map_err(|err| block_file_err(path, err))?;
+            remaining -= chunk as u64;
+        }
+    }
+
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|err| block_file_err(path, err))?;
+    file.write_all(contents)
+        .map_err(|err| block_file_err(path, err))
+}
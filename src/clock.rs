@@ -0,0 +1,88 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// this file is responsible for the time words, either from the real clock or a virtual one
+// which only moves when ms is called, so programs that wait are instant and repeatable
+
+#[derive(Debug, Clone)]
+pub(crate) struct Clock {
+    start: Instant,
+    // nanoseconds since the unix epoch, only used when virtual
+    virtual_now: Option<u64>,
+}
+
+impl Clock {
+    pub(crate) fn new() -> Clock {
+        Clock {
+            start: Instant::now(),
+            virtual_now: None,
+        }
+    }
+
+    // starts at the unix epoch, so times and dates are the same on every run
+    pub(crate) fn new_virtual() -> Clock {
+        Clock {
+            start: Instant::now(),
+            virtual_now: Some(0),
+        }
+    }
+
+    pub(crate) fn sleep_ms(&mut self, ms: u64) {
+        match &mut self.virtual_now {
+            Some(now) => *now = now.saturating_add(ms.saturating_mul(1_000_000)),
+            None => std::thread::sleep(Duration::from_millis(ms)),
+        }
+    }
+
+    // nanoseconds since the unix epoch
+    pub(crate) fn now_ns(&self) -> u64 {
+        match self.virtual_now {
+            Some(now) => now,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0),
+        }
+    }
+
+    // milliseconds since the program started
+    pub(crate) fn ticks(&self) -> u64 {
+        match self.virtual_now {
+            Some(now) => now / 1_000_000,
+            None => self.start.elapsed().as_millis() as u64,
+        }
+    }
+
+    // seconds, minutes, hours, day, month and year, in utc
+    pub(crate) fn time_and_date(&self) -> [i64; 6] {
+        let seconds = (self.now_ns() / 1_000_000_000) as i64;
+        let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+        let seconds_of_day = seconds.rem_euclid(86400);
+        [
+            seconds_of_day % 60,
+            seconds_of_day / 60 % 60,
+            seconds_of_day / 3600,
+            day,
+            month,
+            year,
+        ]
+    }
+}
+
+// the date `days` days after 1970-01-01, from Howard Hinnant's date algorithms
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
@@ -0,0 +1,234 @@
+use crate::host::HostWord;
+use crate::parsing::{Word, BUILTIN_WORDS};
+use crate::DefinedWord;
+use ahash::{HashSet, HashSetExt, RandomState};
+use std::collections::HashMap;
+use std::fmt;
+
+// this file is responsible for the dictionary, the single place every name is looked up in
+
+// where a definition came from
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SourceLocation {
+    pub(crate) source: String,
+    pub(crate) line: usize,
+}
+
+impl SourceLocation {
+    pub(crate) fn new(source: &str, line: usize) -> SourceLocation {
+        SourceLocation {
+            source: source.to_string(),
+            line,
+        }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.source, self.line)
+    }
+}
+
+// what a name refers to
+// addresses are into state.memory
+#[derive(Debug, Clone)]
+pub(crate) enum EntryKind {
+    Colon(DefinedWord),
+    Variable(i64),
+    Constant(i64),
+    Value(i64),
+    TwoVariable(i64),
+    TwoConstant(i64, i64),
+    TwoValue(i64),
+    Buffer(i64),
+    // a builtin, only reached through the dictionary when looked up by name
+    Native(Word),
+    // made by create, pushes the address of its data field
+    Created(i64),
+    // a structure field, adds its offset to the address on the stack
+    Field(i64),
+    // registered by the embedding program, never inlined
+    Host(HostWord),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct DictionaryEntry {
+    pub(crate) name: String,
+    pub(crate) kind: EntryKind,
+    pub(crate) location: SourceLocation,
+    // hidden entries are never found by name
+    pub(crate) hidden: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Dictionary {
+    // in definition order, so the index of an entry is its definition order
+    entries: Vec<DictionaryEntry>,
+    // every name ever looked up or defined gets a symbol, so compiled code can call by number
+    symbols: HashMap<String, usize, RandomState>,
+    names: Vec<String>,
+    // index of the newest visible entry for each symbol
+    latest: Vec<Option<usize>>,
+    // for each symbol, the colon definitions with what it meant inlined into them
+    // kept up to date by set_colon, so redefining a name only visits the words it affects
+    inlined_into: Vec<HashSet<usize>>,
+}
+
+impl Dictionary {
+    pub(crate) fn new() -> Dictionary {
+        let mut dictionary = Dictionary {
+            entries: Vec::with_capacity(BUILTIN_WORDS.len() + 16),
+            symbols: HashMap::with_capacity_and_hasher(
+                BUILTIN_WORDS.len() + 16,
+                RandomState::new(),
+            ),
+            names: Vec::with_capacity(BUILTIN_WORDS.len() + 16),
+            latest: Vec::with_capacity(BUILTIN_WORDS.len() + 16),
+            inlined_into: Vec::with_capacity(BUILTIN_WORDS.len() + 16),
+        };
+
+        let location = SourceLocation::new("builtin", 0);
+        for (name, word) in BUILTIN_WORDS {
+            dictionary.define(name, EntryKind::Native(word.clone()), location.clone());
+        }
+
+        dictionary
+    }
+
+    // adds a new entry, shadowing anything defined earlier with the same name
+    pub(crate) fn define(
+        &mut self,
+        name: &str,
+        kind: EntryKind,
+        location: SourceLocation,
+    ) -> usize {
+        let index = self.entries.len();
+        self.entries.push(DictionaryEntry {
+            name: name.to_string(),
+            kind,
+            location,
+            hidden: false,
+        });
+        let symbol = self.intern(name);
+        self.latest[symbol] = Some(index);
+        index
+    }
+
+    // adds an entry that can only be reached by its index, like a quotation's
+    pub(crate) fn define_hidden(
+        &mut self,
+        name: &str,
+        kind: EntryKind,
+        location: SourceLocation,
+    ) -> usize {
+        self.entries.push(DictionaryEntry {
+            name: name.to_string(),
+            kind,
+            location,
+            hidden: true,
+        });
+        self.entries.len() - 1
+    }
+
+    // the symbol for a name, made the first time the name is seen
+    pub(crate) fn intern(&mut self, name: &str) -> usize {
+        if let Some(symbol) = self.symbols.get(name) {
+            return *symbol;
+        }
+        let symbol = self.names.len();
+        self.symbols.insert(name.to_string(), symbol);
+        self.names.push(name.to_string());
+        self.latest.push(None);
+        self.inlined_into.push(HashSet::new());
+        symbol
+    }
+
+    pub(crate) fn symbol_name(&self, symbol: usize) -> &str {
+        &self.names[symbol]
+    }
+
+    // the entry a symbol currently refers to, without hashing its name
+    #[inline]
+    pub(crate) fn resolve(&self, symbol: usize) -> Option<usize> {
+        self.latest[symbol]
+    }
+
+    pub(crate) fn find_index(&self, name: &str) -> Option<usize> {
+        self.symbols
+            .get(name)
+            .and_then(|symbol| self.latest[*symbol])
+    }
+
+    pub(crate) fn find(&self, name: &str) -> Option<&DictionaryEntry> {
+        self.find_index(name).map(|index| &self.entries[index])
+    }
+
+    pub(crate) fn get(&self, index: usize) -> Option<&DictionaryEntry> {
+        self.entries.get(index)
+    }
+
+    // the colon definition a name currently refers to, if it refers to one
+    pub(crate) fn colon(&self, name: &str) -> Option<&DefinedWord> {
+        match self.find(name) {
+            Some(DictionaryEntry {
+                kind: EntryKind::Colon(defined_word),
+                ..
+            }) => Some(defined_word),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn set_colon(&mut self, index: usize, defined_word: DefinedWord) {
+        for name in &defined_word.depends_on {
+            let symbol = self.intern(name);
+            self.inlined_into[symbol].insert(index);
+        }
+        self.entries[index].kind = EntryKind::Colon(defined_word);
+    }
+
+    // the colon definitions name was inlined into, forgetting them
+    // they may have been redefined or had their inlining broken since, so callers check again
+    pub(crate) fn take_inlined_into(&mut self, name: &str) -> HashSet<usize> {
+        match self.symbols.get(name) {
+            Some(symbol) => std::mem::take(&mut self.inlined_into[*symbol]),
+            None => HashSet::new(),
+        }
+    }
+
+    pub(crate) fn set_kind(&mut self, index: usize, kind: EntryKind) {
+        self.entries[index].kind = kind;
+    }
+
+    pub(crate) fn entries(&self) -> impl Iterator<Item = (usize, &DictionaryEntry)> {
+        self.entries.iter().enumerate()
+    }
+
+    // hides everything that isn't code, for reset
+    // returns the names hidden, whose values may have been inlined into colon definitions
+    pub(crate) fn hide_data_words(&mut self) -> Vec<String> {
+        let mut hidden = Vec::new();
+        for entry in self.entries.iter_mut() {
+            match &mut entry.kind {
+                // a name can now mean an older definition, with a different stack effect
+                EntryKind::Colon(word) => word.effect = None,
+                EntryKind::Native(_) => {}
+                _ if entry.hidden => {}
+                _ => {
+                    entry.hidden = true;
+                    hidden.push(entry.name.clone());
+                }
+            }
+        }
+        self.rebuild_latest();
+        hidden
+    }
+
+    fn rebuild_latest(&mut self) {
+        self.latest.fill(None);
+        for (index, entry) in self.entries.iter().enumerate() {
+            if !entry.hidden {
+                self.latest[self.symbols[&entry.name]] = Some(index);
+            }
+        }
+    }
+}
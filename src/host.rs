@@ -0,0 +1,111 @@
+use crate::{underflow_err, Error};
+use std::fmt;
+use std::io::Write;
+use std::rc::Rc;
+
+// this file is responsible for words implemented by the program embedding the interpreter
+// the interpreter knows nothing about them except their stack effect, so they are never inlined
+
+type HostFn = dyn Fn(&mut HostContext<'_>) -> Result<(), Error>;
+
+// a word registered through Forth::register
+#[derive(Clone)]
+pub(crate) struct HostWord {
+    name: String,
+    inputs: usize,
+    outputs: usize,
+    run: Rc<HostFn>,
+}
+
+impl fmt::Debug for HostWord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HostWord({} {} -- {})",
+            self.name, self.inputs, self.outputs
+        )
+    }
+}
+
+/// What a registered word can see of the interpreter while it runs.
+pub struct HostContext<'a> {
+    stack: &'a mut Vec<i64>,
+    // the cells below the word's inputs belong to its callers
+    floor: usize,
+    output: &'a mut dyn Write,
+}
+
+impl HostContext<'_> {
+    /// Pushes `value` onto the data stack.
+    pub fn push(&mut self, value: i64) {
+        self.stack.push(value);
+    }
+
+    /// Pops the top of the data stack, failing rather than taking a cell below the word's
+    /// declared inputs.
+    pub fn pop(&mut self) -> Result<i64, Error> {
+        if self.stack.len() <= self.floor {
+            return Err(Error::from(underflow_err().unwrap_err()));
+        }
+        Ok(self.stack.pop().unwrap())
+    }
+
+    /// The word's inputs and whatever it has pushed since, top last.
+    pub fn stack(&self) -> &[i64] {
+        &self.stack[self.floor..]
+    }
+
+    /// Where the program's own output goes.
+    pub fn output(&mut self) -> &mut dyn Write {
+        self.output
+    }
+}
+
+impl HostWord {
+    pub(crate) fn new(
+        name: &str,
+        inputs: usize,
+        outputs: usize,
+        run: impl Fn(&mut HostContext<'_>) -> Result<(), Error> + 'static,
+    ) -> HostWord {
+        HostWord {
+            name: name.to_string(),
+            inputs,
+            outputs,
+            run: Rc::new(run),
+        }
+    }
+
+    pub(crate) fn inputs(&self) -> usize {
+        self.inputs
+    }
+
+    pub(crate) fn outputs(&self) -> usize {
+        self.outputs
+    }
+
+    // runs the word, holding it to the stack effect it was registered with
+    pub(crate) fn call(&self, stack: &mut Vec<i64>, output: &mut dyn Write) -> Result<(), String> {
+        if stack.len() < self.inputs {
+            return underflow_err();
+        }
+        let floor = stack.len() - self.inputs;
+        let expected_depth = floor + self.outputs;
+
+        let mut context = HostContext {
+            stack,
+            floor,
+            output,
+        };
+        (self.run)(&mut context).map_err(|e| e.to_string())?;
+
+        if stack.len() != expected_depth {
+            // the caller adds which word it was
+            return Err(format!(
+                "Left the stack {} deep instead of {expected_depth}",
+                stack.len()
+            ));
+        }
+        Ok(())
+    }
+}
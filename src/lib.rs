@@ -0,0 +1,2094 @@
+mod blocks;
+mod clock;
+mod dictionary;
+mod host;
+mod memory;
+mod optimizer;
+mod output_cplusplus;
+mod output_test;
+mod parsing;
+mod stack_effect;
+mod terminal;
+mod vm;
+
+use crate::blocks::{BlockStore, BLOCK_LINES, BLOCK_LINE_LENGTH, BLOCK_SIZE, DEFAULT_BLOCK_FILE};
+use crate::clock::Clock;
+use crate::dictionary::{Dictionary, EntryKind, SourceLocation};
+pub use crate::host::HostContext;
+use crate::memory::{Memory, ALLOCATE_IOR, CELL_SIZE, RESIZE_IOR};
+use crate::optimizer::OptimizerSettings;
+use crate::output_cplusplus::output_cplusplus;
+use crate::parsing::{parse_line, Word, BUILTIN_WORDS};
+use crate::stack_effect::StackEffect;
+use crate::terminal::{KeySource, Terminal};
+pub use crate::vm::Forth;
+use ahash::{HashSet, HashSetExt, RandomState};
+use std::collections::HashMap;
+use std::io::Write;
+use std::rc::Rc;
+use std::string::ToString;
+
+// how deep colon definitions can call each other before it's treated as runaway recursion
+const DEFAULT_MAX_RETURN_DEPTH: usize = 10_000;
+const MAX_INPUT_NESTING: usize = 64;
+const PRELUDE: &str = include_str!("prelude.forth");
+// the ior substitute returns when the result doesn't fit in the buffer
+pub(crate) const SUBSTITUTE_IOR: i64 = -78;
+
+fn underflow_err() -> Result<(), String> {
+    Err("Stack Underflow".to_string())
+}
+
+fn return_stack_overflow_err() -> Result<(), String> {
+    Err("Return Stack Overflow".to_string())
+}
+
+fn input_nesting_overflow_err() -> Result<(), String> {
+    Err("Input Source Nesting Overflow".to_string())
+}
+
+pub type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+#[derive(Debug, Clone)]
+struct LoopControlStackFrame {
+    index: i64,
+    limit: i64,
+    loop_start: usize,
+    // the index of the line where the loop starts (after DO)
+}
+
+// a colon definition that is running, kept so its caller can carry on when it returns
+#[derive(Clone)]
+struct ReturnFrame {
+    // the caller's code and where to carry on in it
+    code: Rc<Vec<Word>>,
+    ip: usize,
+    // how many loops the caller had running, exit drops any the callee left behind
+    loop_depth: usize,
+    // the entry that was called, for error messages
+    entry_index: usize,
+    // whether the caller's primitives check the stack depth, see inner_interpreter
+    checked: bool,
+}
+
+// the caller's code is left out, a deep return stack would print the same code thousands of times
+impl std::fmt::Debug for ReturnFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ReturnFrame(entry {} from ip {})",
+            self.entry_index, self.ip
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DefinedWord {
+    words: Rc<Vec<Word>>,
+    original_words: Rc<Vec<Word>>,
+    // so we can revert inlining
+    has_been_inlined: bool,
+    inline_count: i32,
+    // number of times tried to inline, prevents recursion
+    depends_on: HashSet<String>, // the defined words that have been inlined into this word
+    // inlined all the way down whenever it is inlined, so callers never pay for calling it
+    always_inline: bool,
+    // worked out at ; from original_words, none if it couldn't be
+    effect: Option<StackEffect>,
+}
+
+// where the text currently being interpreted came from, the main input is implied when empty
+#[derive(Debug, Clone)]
+enum InputSource {
+    // a string handed to EVALUATE
+    Evaluate,
+    // a block being loaded
+    Block(i64),
+    // the bundled prelude, loaded at startup
+    Prelude,
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    dictionary: Dictionary,
+    // where the line being interpreted came from, recorded in new definitions
+    location: SourceLocation,
+    loop_control_stack: Vec<LoopControlStackFrame>,
+    // the colon definitions running inside each other, innermost last
+    return_stack: Vec<ReturnFrame>,
+    max_return_depth: usize,
+    optimizer: OptimizerSettings,
+    input_sources: Vec<InputSource>,
+    // how many [if]s deep we are in source text being skipped, 0 when not skipping
+    conditional_skip: usize,
+    // [if]s whose [then] hasn't been seen yet, skipped or not
+    open_conditionals: usize,
+    // every active {: :} frame's locals, locals_frames holds where each frame starts
+    locals: Vec<i64>,
+    locals_frames: Vec<usize>,
+
+    memory: Memory,
+    blocks: BlockStore,
+    terminal: Terminal,
+    clock: Clock,
+    // execution tokens of the quotations run so far, so running one again doesn't define it again
+    quotations: HashMap<Vec<Word>, usize, RandomState>,
+    // the text substitute puts in place of each %name%, keyed by lower case name
+    substitutions: HashMap<String, Vec<u8>, RandomState>,
+    // problems found in definitions that don't stop them being used, until the host takes them
+    warnings: Vec<String>,
+
+    // simply a buffer for certain operations
+    // must be cleared before use, no guarantees about state
+    internal_buffer: Vec<u8>,
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            dictionary: Dictionary::new(),
+            location: SourceLocation::new("input", 0),
+            loop_control_stack: Vec::with_capacity(3),
+            return_stack: Vec::with_capacity(16),
+            max_return_depth: DEFAULT_MAX_RETURN_DEPTH,
+            optimizer: OptimizerSettings::default(),
+            input_sources: Vec::with_capacity(3),
+            conditional_skip: 0,
+            open_conditionals: 0,
+            locals: Vec::with_capacity(10),
+            locals_frames: Vec::with_capacity(3),
+            memory: Memory::new(),
+            quotations: HashMap::with_capacity_and_hasher(5, RandomState::new()),
+            blocks: BlockStore::new(std::path::PathBuf::from(DEFAULT_BLOCK_FILE)),
+            terminal: Terminal::new(KeySource::Stdin, false),
+            clock: Clock::new(),
+            substitutions: HashMap::with_capacity_and_hasher(5, RandomState::new()),
+            warnings: Vec::new(),
+            internal_buffer: Vec::with_capacity(10),
+        }
+    }
+}
+
+fn try_output_cpp(parsed_line: &[Word], state: &State) -> Option<String> {
+    if parsed_line.len() == 1 {
+        let func = parsed_line[0].clone();
+        if let Word::Word(x) = func {
+            //make sure it's fully optimized and loop till it is
+            return if let Some(line) = state.dictionary.colon(&x) {
+                let to_use = if !line.has_been_inlined {
+                    let (out, _) = parsing::inline_fully(&x, &line.words, state);
+                    out
+                } else {
+                    line.words.to_vec()
+                };
+                let output = output_cplusplus(&to_use);
+                Some(output)
+            } else {
+                Some("Word needs to be defined to generate cpp".to_string())
+            };
+        }
+    }
+    None
+}
+
+// defines a colon definition, working out its stack effect now that its callees are known
+fn define_colon(state: &mut State, name: &str, words: Vec<Word>) {
+    let words = Rc::new(words);
+    let mut defined_word = DefinedWord {
+        words: words.clone(),
+        original_words: words,
+        has_been_inlined: false,
+        inline_count: 0,
+        depends_on: HashSet::new(),
+        always_inline: false,
+        effect: None,
+    };
+    // defined first, so recursive calls find it rather than an older definition
+    let index = define(state, name, EntryKind::Colon(defined_word.clone()));
+
+    let mut depends = HashSet::new();
+    match stack_effect::infer(&defined_word.words, &state.dictionary, index, &mut depends) {
+        Ok(Some(effect)) => {
+            // redefining any of depends makes the effect wrong, so breaking on them clears it
+            defined_word.effect = Some(effect);
+            defined_word.depends_on = depends;
+            state.dictionary.set_colon(index, defined_word);
+        }
+        Ok(None) => {}
+        Err(problem) => state.warnings.push(format!("{name} {problem}")),
+    }
+}
+
+// (re)define a name, undoing any inlining that used the old meaning
+fn define(state: &mut State, name: &str, kind: EntryKind) -> usize {
+    let index = state.dictionary.define(name, kind, state.location.clone());
+    parsing::break_inlining(name.to_string(), state);
+    index
+}
+
+// normalize, parse and run a single line of source, the same way main does for the input file
+// [if] [else] and [then] are handled here, since they decide which source text gets parsed at all
+fn interpret_line(
+    stack: &mut Vec<i64>,
+    state: &mut State,
+    line: String,
+    writer: &mut dyn Write,
+) -> Result<String, Error> {
+    let normalized = parsing::normalize_line(line);
+    let words: Vec<&str> = normalized.split(' ').collect();
+
+    // the words seen since the last conditional, parsed and run as one piece
+    let mut pending: Vec<&str> = Vec::with_capacity(words.len());
+    let mut in_definition = false;
+    // where the definition being read starts in pending
+    let mut definition_start = 0;
+
+    let mut i = 0;
+    while i < words.len() {
+        let word = words[i];
+        if word == "\\" {
+            break;
+        }
+
+        if state.conditional_skip > 0 {
+            match word {
+                "[if]" => state.conditional_skip += 1,
+                "[else]" if state.conditional_skip == 1 => state.conditional_skip = 0,
+                "[then]" => {
+                    state.conditional_skip -= 1;
+                    if state.conditional_skip == 0 {
+                        state.open_conditionals -= 1;
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+            continue;
+        }
+
+        let quote_last_index = parsing::skip_quote(i, &words);
+        if quote_last_index != i {
+            pending.extend(&words[i..quote_last_index.min(words.len())]);
+            i = quote_last_index;
+            continue;
+        }
+
+        // words acting while a definition is compiled see the stack left by what came before it
+        let immediate = matches!(
+            word,
+            "[if]" | "[else]" | "[then]" | "[defined]" | "[undefined]"
+        );
+        if immediate && in_definition && definition_start > 0 {
+            run_source(stack, state, &pending[..definition_start], writer)?;
+            pending.drain(..definition_start);
+            definition_start = 0;
+        }
+
+        match word {
+            "[if]" | "[else]" | "[then]" => {
+                // inside a definition they act while it is compiled, so the definition keeps going
+                // outside one everything before them runs first, since the flag may come from it
+                if !in_definition {
+                    run_source(stack, state, &pending, writer)?;
+                    pending.clear();
+                }
+
+                if word != "[if]" && state.open_conditionals == 0 {
+                    return Err(Error::from(format!("{word} without [if]")));
+                }
+                match word {
+                    "[if]" => {
+                        match stack.pop() {
+                            Some(0) => state.conditional_skip = 1,
+                            Some(_) => {}
+                            None => return Err(Error::from(underflow_err().unwrap_err())),
+                        }
+                        state.open_conditionals += 1;
+                    }
+                    // only reached after running the true part, so skip the false part
+                    "[else]" => state.conditional_skip = 1,
+                    _ => state.open_conditionals -= 1,
+                }
+            }
+            // immediate, so inside a definition they leave their flag now, for an [if] to take
+            "[defined]" | "[undefined]" if in_definition => {
+                let end = (i + 2).min(words.len());
+                run_source(stack, state, &words[i..end], writer)?;
+                i = end;
+                continue;
+            }
+            _ => {
+                match word {
+                    ":" if !in_definition => {
+                        in_definition = true;
+                        definition_start = pending.len();
+                    }
+                    ";" => in_definition = false,
+                    _ => {}
+                }
+                pending.push(word);
+            }
+        }
+        i += 1;
+    }
+
+    run_source(stack, state, &pending, writer)
+}
+
+// replaces every %name% set by replaces, %% becomes a single %
+// returns the new text and how many names were replaced
+fn substitute(state: &State, addr: i64, len: i64) -> Result<(Vec<u8>, i64), String> {
+    let source = state.memory.bytes(addr, len.max(0) as usize)?;
+    let mut result = Vec::with_capacity(source.len());
+    let mut count = 0;
+
+    let mut i = 0;
+    while i < source.len() {
+        let end = match source[i] {
+            b'%' => source[i + 1..].iter().position(|byte| *byte == b'%'),
+            _ => None,
+        };
+        match end {
+            Some(0) => {
+                result.push(b'%');
+                i += 2;
+            }
+            Some(name_len) => {
+                let name = &source[i + 1..i + 1 + name_len];
+                let name = String::from_utf8_lossy(name).to_lowercase();
+                match state.substitutions.get(&name) {
+                    Some(replacement) => {
+                        result.extend_from_slice(replacement);
+                        count += 1;
+                    }
+                    // unknown names are left as they are
+                    None => result.extend_from_slice(&source[i..i + name_len + 2]),
+                }
+                i += name_len + 2;
+            }
+            None => {
+                result.push(source[i]);
+                i += 1;
+            }
+        }
+    }
+
+    Ok((result, count))
+}
+
+fn run_source(
+    stack: &mut Vec<i64>,
+    state: &mut State,
+    words: &[&str],
+    writer: &mut dyn Write,
+) -> Result<String, Error> {
+    let mut parsed_line = parse_line(words.join(" "), state)?;
+    parsing::intern_calls(&mut parsed_line, &mut state.dictionary);
+    parsing::place_strings(&mut parsed_line, &mut state.memory);
+    run_line(stack, state, Rc::new(parsed_line), writer)
+}
+
+// interpret a whole string (possibly several lines) as the new input source, restoring the old one after
+// interprets text line by line as if it were the main input, for evaluate and load
+fn interpret_source(
+    stack: &mut Vec<i64>,
+    state: &mut State,
+    input: InputSource,
+    source: &str,
+    writer: &mut dyn Write,
+) -> Result<(), Error> {
+    if state.input_sources.len() >= MAX_INPUT_NESTING {
+        return Err(Error::from(input_nesting_overflow_err().unwrap_err()));
+    }
+
+    let source_name = match input {
+        InputSource::Evaluate => "evaluate".to_string(),
+        InputSource::Prelude => "prelude".to_string(),
+        InputSource::Block(block) => format!("block {block}"),
+    };
+    state.input_sources.push(input);
+    let outer_location = state.location.clone();
+    let result = source
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.is_empty())
+        .try_for_each(|(line_number, l)| {
+            state.location = SourceLocation::new(&source_name, line_number + 1);
+            interpret_line(stack, state, l.to_string(), writer).map(|_| ())
+        });
+    state.location = outer_location;
+    state.input_sources.pop();
+
+    result
+}
+
+// defines the prelude's words, already fully inlined and marked to stay that way
+fn prelude(stack: &mut Vec<i64>, state: &mut State) -> Result<(), Error> {
+    let first_index = state.dictionary.entries().count();
+    interpret_source(
+        stack,
+        state,
+        InputSource::Prelude,
+        PRELUDE,
+        &mut std::io::sink(),
+    )?;
+
+    if !state.optimizer.optimizing {
+        return Ok(());
+    }
+
+    // in definition order, so each word's callees are already flat when it is inlined
+    for index in first_index..state.dictionary.entries().count() {
+        let entry = state.dictionary.get(index).unwrap();
+        if let EntryKind::Colon(word) = &entry.kind {
+            let (inlined, depends) = parsing::inline_fully(&entry.name, &word.words, state);
+            let new_word = DefinedWord {
+                words: Rc::new(inlined),
+                original_words: word.original_words.clone(),
+                has_been_inlined: true,
+                inline_count: word.inline_count + 1,
+                depends_on: depends,
+                always_inline: true,
+                effect: word.effect,
+            };
+            state.dictionary.set_colon(index, new_word);
+        }
+    }
+    Ok(())
+}
+
+// a block's text, one line per 64 characters with the padding taken off
+fn block_text(state: &mut State, block: i64) -> Result<String, String> {
+    let addr = state.blocks.block(&mut state.memory, block)?;
+    let contents = state.memory.bytes(addr, BLOCK_SIZE)?;
+    let lines: Vec<String> = contents
+        .chunks(BLOCK_LINE_LENGTH)
+        .map(|line| String::from_utf8_lossy(line).trim_end().to_string())
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+fn load(
+    stack: &mut Vec<i64>,
+    state: &mut State,
+    block: i64,
+    writer: &mut dyn Write,
+) -> Result<(), String> {
+    // copied out first, since loading may reuse the buffer for other blocks
+    let text = block_text(state, block)?;
+    interpret_source(stack, state, InputSource::Block(block), &text, writer)
+        .map_err(|e| e.to_string())
+}
+
+// runs code and every colon definition it calls, returning once the code itself finishes
+fn run_line(
+    stack: &mut Vec<i64>,
+    state: &mut State,
+    code: Rc<Vec<Word>>,
+    writer: &mut dyn Write,
+) -> Result<String, Error> {
+    // calls made by whatever is running this line (evaluate inside a word) stay below base
+    let base = state.return_stack.len();
+    // and so do its loops and locals frames
+    let loop_base = state.loop_control_stack.len();
+    let locals_base = state.locals.len();
+    let locals_frames_base = state.locals_frames.len();
+    let result = inner_interpreter(stack, state, code, base, writer);
+    if let Err(e) = result {
+        // name every definition the error happened inside, innermost first
+        // with recursion collapsed, it would otherwise name the same word thousands of times
+        let mut message = e.to_string();
+        let frames = &state.return_stack[base..];
+        let mut top = frames.len();
+        while top > 0 {
+            let entry_index = frames[top - 1].entry_index;
+            let mut calls = 1;
+            while top > calls && frames[top - 1 - calls].entry_index == entry_index {
+                calls += 1;
+            }
+            let entry = state.dictionary.get(entry_index).unwrap();
+            message += &format!(" in {} ({})", entry.name, entry.location);
+            if calls > 1 {
+                message += &format!(" ({calls} calls deep)");
+            }
+            top -= calls;
+        }
+        state.return_stack.truncate(base);
+        state.loop_control_stack.truncate(loop_base);
+        state.locals.truncate(locals_base);
+        state.locals_frames.truncate(locals_frames_base);
+        return Err(Error::from(message));
+    }
+    result
+}
+
+// colon definitions are called by switching code and pushing the caller on the return stack
+// rather than recursing, so how deep they can go doesn't depend on the native stack
+fn inner_interpreter(
+    stack: &mut Vec<i64>,
+    state: &mut State,
+    mut code: Rc<Vec<Word>>,
+    base: usize,
+    writer: &mut dyn Write,
+) -> Result<String, Error> {
+    let mut i = 0;
+    let mut loop_depth = state.loop_control_stack.len();
+    // a definition with a known stack effect called with enough on the stack can't underflow,
+    // so its primitives don't need to check for it
+    let mut checked = true;
+
+    loop {
+        if i >= code.len() {
+            // the end of a definition returns to its caller, the end of the line finishes
+            if state.return_stack.len() == base {
+                return Ok("OK".to_string());
+            }
+            let frame = state.return_stack.pop().unwrap();
+            code = frame.code;
+            i = frame.ip;
+            loop_depth = frame.loop_depth;
+            checked = frame.checked;
+            continue;
+        }
+
+        //for profiling
+        // coz::progress!();
+
+        let line_len = code.len();
+        let word = &code[i];
+
+        match word {
+            Word::Quote(out) => {
+                writer
+                    .write_all(out.as_ref())
+                    .expect("Could not write output");
+            }
+            Word::Function(function_name) => {
+                // collect whole function, then resume later
+                let mut func_index = i + 1;
+                while func_index < line_len
+                    && !matches!(code.get(func_index).unwrap(), Word::EndFunction)
+                {
+                    func_index += 1;
+                }
+                let mut function = code[i + 1..func_index].to_vec();
+                // TODO if last index isn't ; then error
+                // the jump targets were worked out for the whole line, they need to be into the body instead
+                parsing::resolve_control_flow(&mut function)?;
+                define_colon(state, function_name, function);
+
+                i = func_index
+            }
+            Word::Variable(name) => {
+                let addr = state.memory.allot(CELL_SIZE as usize);
+                state.memory.store_cell(addr, 0)?;
+                define(state, name, EntryKind::Variable(addr));
+            }
+            Word::Constant(name) => {
+                if stack.is_empty() {
+                    return Err(Error::from(underflow_err().unwrap_err()));
+                }
+                let val = stack.pop().unwrap();
+                define(state, name, EntryKind::Constant(val));
+            }
+            Word::Loop => {
+                if let Some(last) = state.loop_control_stack.last_mut() {
+                    last.index += 1;
+
+                    if last.index < last.limit {
+                        i = last.loop_start;
+                    } else {
+                        i += 1;
+                        state.loop_control_stack.pop(); //pop if we are done with loop
+                    }
+                    continue;
+                } else {
+                    return Err(Error::from(underflow_err().unwrap_err()));
+                }
+            }
+            Word::PlusLoop => {
+                if let Some(last) = state.loop_control_stack.last_mut() {
+                    if stack.is_empty() {
+                        return Err(Error::from(underflow_err().unwrap_err()));
+                    }
+                    let increment = stack.pop().unwrap();
+
+                    last.index += increment;
+
+                    if last.index < last.limit {
+                        i = last.loop_start;
+                    } else {
+                        i += 1;
+                        state.loop_control_stack.pop(); //pop if we are done with loop
+                    }
+                    continue;
+                } else {
+                    return Err(Error::from(underflow_err().unwrap_err()));
+                }
+            }
+            // Optimize case where increase is a constant
+            Word::PlusLoopConst(constant) => {
+                if let Some(last) = state.loop_control_stack.last_mut() {
+                    last.index += constant;
+
+                    if last.index < last.limit {
+                        i = last.loop_start;
+                    } else {
+                        i += 1;
+                        state.loop_control_stack.pop(); //pop if we are done with loop
+                    }
+                    continue;
+                } else {
+                    return Err(Error::from(underflow_err().unwrap_err()));
+                }
+            }
+            Word::If(target) => match stack.pop() {
+                Some(0) => {
+                    i = *target;
+                    continue;
+                }
+                Some(_) => {}
+                None => return Err(Error::from(underflow_err().unwrap_err())),
+            },
+            // this is an optimization, `0 = if`
+            Word::NotIf(target) => match stack.pop() {
+                Some(0) => {}
+                Some(_) => {
+                    i = *target;
+                    continue;
+                }
+                None => return Err(Error::from(underflow_err().unwrap_err())),
+            },
+            Word::Of(target) => {
+                if stack.len() < 2 {
+                    return Err(Error::from(underflow_err().unwrap_err()));
+                }
+
+                // only drop the selector too if it matches
+                let value = stack.pop().unwrap();
+                if *stack.last().unwrap() == value {
+                    stack.pop();
+                } else {
+                    i = *target;
+                    continue;
+                }
+            }
+            // only reached at the end of the part that ran, so these always jump
+            Word::Else(target) | Word::EndOf(target) => {
+                i = *target;
+                continue;
+            }
+            Word::Leave(target) => {
+                state.loop_control_stack.pop();
+                i = *target;
+                continue;
+            }
+            Word::Exit => {
+                // loops this left without unloop would otherwise be taken for the caller's
+                state.loop_control_stack.truncate(loop_depth);
+                i = line_len;
+                continue;
+            }
+            Word::Word(_) | Word::Call(_) | Word::Execute => {
+                let entry_index = callee(stack, state, word)?;
+                if let Some((body, effect)) = colon_code(state, entry_index) {
+                    // only when optimizing, since what it would print or store before
+                    // underflowing is skipped too
+                    let optimizing = state.optimizer.optimizing;
+                    if let Some(effect) =
+                        effect.filter(|e| optimizing && stack.len() < e.certain_inputs)
+                    {
+                        // every way through it underflows, so don't run any of it
+                        let name = &state.dictionary.get(entry_index).unwrap().name;
+                        return Err(Error::from(format!(
+                            "Stack Underflow, {name} takes at least {} cells",
+                            effect.certain_inputs
+                        )));
+                    }
+                    if state.return_stack.len() >= state.max_return_depth {
+                        return Err(Error::from(return_stack_overflow_err().unwrap_err()));
+                    }
+                    state.return_stack.push(ReturnFrame {
+                        code: std::mem::replace(&mut code, body),
+                        ip: i + 1,
+                        loop_depth,
+                        entry_index,
+                        checked,
+                    });
+                    i = 0;
+                    loop_depth = state.loop_control_stack.len();
+                    checked = effect.is_none_or(|effect| stack.len() < effect.inputs);
+                    continue;
+                }
+                if let Err(e) = run_entry(stack, state, i, entry_index, writer) {
+                    return Err(report_error(state, word, e));
+                }
+            }
+            // run everything else through run_word
+            _ => {
+                if checked || !run_unchecked(stack, word) {
+                    if let Err(e) = run_word(stack, state, i, word, writer) {
+                        return Err(report_error(state, word, e));
+                    }
+                }
+            }
+        }
+
+        i += 1;
+    }
+}
+
+// the error with the word that failed, which the message alone may not name
+fn report_error(state: &State, word: &Word, e: String) -> Error {
+    Error::from(format!("{e} at {}", word_name(state, word)))
+}
+
+// a word the way it is written in source, or as parsed when it has no one name
+fn word_name(state: &State, word: &Word) -> String {
+    match word {
+        Word::Word(name) => name.clone(),
+        Word::Call(symbol) => state.dictionary.symbol_name(*symbol).to_string(),
+        _ => match BUILTIN_WORDS.iter().find(|(_, builtin)| builtin == word) {
+            Some((name, _)) => name.to_string(),
+            None => format!("{word:?}"),
+        },
+    }
+}
+
+// the dictionary entry a call runs, execute takes it from the stack
+fn callee(stack: &mut Vec<i64>, state: &State, word: &Word) -> Result<usize, String> {
+    match word {
+        Word::Word(name) => state
+            .dictionary
+            .find_index(name)
+            .ok_or_else(|| format!("Unrecognized word {word:?}")),
+        Word::Call(symbol) => state.dictionary.resolve(*symbol).ok_or_else(|| {
+            let name = state.dictionary.symbol_name(*symbol);
+            format!("Unrecognized word {:?}", Word::Word(name.to_string()))
+        }),
+        Word::Execute => match stack.pop() {
+            Some(xt) if xt < 0 || state.dictionary.get(xt as usize).is_none() => {
+                Err(format!("Invalid execution token {xt}"))
+            }
+            Some(xt) => Ok(xt as usize),
+            None => Err(underflow_err().unwrap_err()),
+        },
+        _ => unreachable!(),
+    }
+}
+
+// the most used primitives without their stack depth checks, false for anything else
+// only for code whose stack effect proves it has the cells, the indexing still panics otherwise
+fn run_unchecked(stack: &mut Vec<i64>, word: &Word) -> bool {
+    let len = stack.len();
+    match word {
+        Word::Number(n) => stack.push(*n),
+        Word::Plus => {
+            stack[len - 2] += stack[len - 1];
+            stack.truncate(len - 1);
+        }
+        Word::Mult => {
+            stack[len - 2] *= stack[len - 1];
+            stack.truncate(len - 1);
+        }
+        Word::Equal => {
+            stack[len - 2] = (stack[len - 2] == stack[len - 1]) as i64;
+            stack.truncate(len - 1);
+        }
+        Word::Greater => {
+            stack[len - 2] = (stack[len - 2] > stack[len - 1]) as i64;
+            stack.truncate(len - 1);
+        }
+        Word::Less => {
+            stack[len - 2] = (stack[len - 2] < stack[len - 1]) as i64;
+            stack.truncate(len - 1);
+        }
+        Word::OnePlus => stack[len - 1] += 1,
+        Word::EqZero => stack[len - 1] = (stack[len - 1] == 0) as i64,
+        Word::Dup => stack.push(stack[len - 1]),
+        Word::DupModConst(n) => stack.push(stack[len - 1] % n),
+        Word::Drop => stack.truncate(len - 1),
+        Word::Swap => stack.swap(len - 2, len - 1),
+        Word::Over => stack.push(stack[len - 2]),
+        Word::Nip => {
+            stack.swap_remove(len - 2);
+        }
+        Word::Rot => stack[len - 3..].rotate_left(1),
+        Word::DoubleRot => stack[len - 3..].rotate_right(1),
+        Word::TwoDup => stack.extend_from_within(len - 2..),
+        Word::TwoDrop => stack.truncate(len - 2),
+        Word::Then => {}
+        _ => return false,
+    }
+    true
+}
+
+// the code to run for a colon definition and its stack effect, inlining into it the first few
+// times it's called
+fn colon_code(
+    state: &mut State,
+    entry_index: usize,
+) -> Option<(Rc<Vec<Word>>, Option<StackEffect>)> {
+    let entry = state.dictionary.get(entry_index).unwrap();
+    let EntryKind::Colon(cmd) = &entry.kind else {
+        return None;
+    };
+    if !state.optimizer.optimizing {
+        return Some((cmd.original_words.clone(), cmd.effect));
+    }
+
+    // this is a slow path, but that's fine because it is only run a few times per function
+    // note the 16 here prevents functions from being unrolled recursively
+    if !cmd.has_been_inlined && cmd.inline_count < 16 {
+        let command = cmd.clone();
+        let name = entry.name.clone();
+        let (inlined, mut depends) = if command.always_inline {
+            parsing::inline_fully(&name, &command.words, state)
+        } else {
+            parsing::inline_function(&name, &command.words, state)
+        };
+        let len = inlined.len();
+        if inlined != *command.words {
+            optimizer::record_rewrite(|| format!("inlining into {name}"));
+        }
+
+        command
+            .depends_on
+            .iter()
+            .for_each(|f| _ = depends.insert(f.clone()));
+
+        let words = Rc::new(inlined);
+        let new_command = DefinedWord {
+            words: words.clone(),
+            original_words: command.original_words.clone(),
+            has_been_inlined: command.always_inline || len == command.words.len(), // only consider a function fully inlined if the size doesn't change
+            inline_count: command.inline_count + 1,
+            depends_on: depends,
+            always_inline: command.always_inline,
+            effect: command.effect,
+        };
+        // words that inlined the old code don't need breaking, inlining doesn't change what it does
+        state.dictionary.set_colon(entry_index, new_command);
+
+        // use the inlined word as soon as possible
+        return Some((words, command.effect));
+    }
+
+    // only the code is needed to run it, and sharing that is just a reference count
+    Some((cmd.words.clone(), cmd.effect))
+}
+
+// runs whatever the dictionary entry at entry_index is
+fn run_entry(
+    stack: &mut Vec<i64>,
+    state: &mut State,
+    index: usize,
+    entry_index: usize,
+    output: &mut dyn Write,
+) -> Result<(), String> {
+    let entry = state.dictionary.get(entry_index).unwrap();
+    match &entry.kind {
+        EntryKind::Colon(_) => {
+            let (code, _) = colon_code(state, entry_index).unwrap();
+            if state.return_stack.len() >= state.max_return_depth {
+                return return_stack_overflow_err();
+            }
+            if let Err(e) = run_line(stack, state, code, output) {
+                let entry = state.dictionary.get(entry_index).unwrap();
+                return Err(format!("{e} in {} ({})", entry.name, entry.location));
+            }
+        }
+        EntryKind::Variable(addr)
+        | EntryKind::TwoVariable(addr)
+        | EntryKind::Buffer(addr)
+        | EntryKind::Created(addr) => stack.push(*addr),
+        EntryKind::Constant(val) => stack.push(*val),
+        EntryKind::Field(offset) => {
+            if let Some(addr) = stack.last_mut() {
+                *addr += offset;
+            } else {
+                return underflow_err();
+            }
+        }
+        EntryKind::Value(addr) => stack.push(state.memory.fetch_cell(*addr)?),
+        EntryKind::TwoConstant(one, two) => {
+            stack.push(*one);
+            stack.push(*two);
+        }
+        EntryKind::TwoValue(addr) => {
+            stack.push(state.memory.fetch_cell(addr + CELL_SIZE)?);
+            stack.push(state.memory.fetch_cell(*addr)?);
+        }
+        EntryKind::Native(word) => {
+            let word = word.clone();
+            return run_word(stack, state, index, &word, output);
+        }
+        EntryKind::Host(word) => return word.call(stack, output),
+    }
+
+    Ok(())
+}
+
+fn run_word(
+    stack: &mut Vec<i64>,
+    state: &mut State,
+    index: usize,
+    word: &Word,
+    output: &mut dyn Write,
+) -> Result<(), String> {
+    // must be an actual word
+    match word {
+        Word::Number(n) => {
+            stack.push(*n);
+        }
+        Word::Plus => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+            let one = stack.pop().unwrap();
+            let two = stack.pop().unwrap();
+            stack.push(one + two);
+        }
+        Word::Dot => {
+            if let Some(val) = stack.pop() {
+                //write value used optimized integer writing
+                itoap::write(output, val).expect("Could not write value");
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Cr => {
+            writeln!(output).expect("Could not write out newline");
+        }
+        Word::UDotR => {
+            //TODO properly implement
+        }
+        Word::Equal => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let one = stack.pop().unwrap();
+            let two = stack.pop().unwrap();
+
+            if one == two {
+                stack.push(1)
+            } else {
+                stack.push(0)
+            }
+        }
+        Word::Greater => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let one = stack.pop().unwrap();
+            let two = stack.pop().unwrap();
+
+            if one < two {
+                stack.push(1)
+            } else {
+                stack.push(0)
+            }
+        }
+        Word::Less => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let one = stack.pop().unwrap();
+            let two = stack.pop().unwrap();
+
+            if one > two {
+                stack.push(1)
+            } else {
+                stack.push(0)
+            }
+        }
+        Word::MultDivide => {
+            if stack.len() < 3 {
+                return underflow_err();
+            }
+
+            let three = stack.pop().unwrap();
+            let two = stack.pop().unwrap();
+            let one = stack.pop().unwrap();
+
+            let inter = one * two;
+            stack.push(inter / three);
+        }
+        Word::Mod => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let two = stack.pop().unwrap();
+            let one = stack.pop().unwrap();
+
+            stack.push(one % two);
+        }
+        Word::Mult => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let two = stack.pop().unwrap();
+            let one = stack.pop().unwrap();
+
+            stack.push(one * two);
+        }
+        Word::At => {
+            if let Some(addr) = stack.pop() {
+                stack.push(state.memory.fetch_cell(addr)?);
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::TwoAt => {
+            if let Some(addr) = stack.pop() {
+                // the cell at the lower address is the top of the stack
+                stack.push(state.memory.fetch_cell(addr + CELL_SIZE)?);
+                stack.push(state.memory.fetch_cell(addr)?);
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::TwoExclamation => {
+            if stack.len() < 3 {
+                return underflow_err();
+            }
+
+            let addr = stack.pop().unwrap();
+            let two = stack.pop().unwrap();
+            let one = stack.pop().unwrap();
+
+            state.memory.store_cell(addr, two)?;
+            state.memory.store_cell(addr + CELL_SIZE, one)?;
+        }
+        Word::Cells => {
+            let len = stack.len();
+            if len >= 1 {
+                stack[len - 1] *= CELL_SIZE
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Dup => {
+            if let Some(one) = stack.last() {
+                stack.push(*one);
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Drop => {
+            if stack.pop().is_none() {
+                return underflow_err();
+            }
+        }
+        Word::Swap => {
+            let len = stack.len();
+            if len < 2 {
+                return underflow_err();
+            }
+            stack.swap(len - 2, len - 1);
+        }
+        Word::Rot => {
+            let len = stack.len();
+            if len < 3 {
+                return underflow_err();
+            }
+
+            stack.swap(len - 1, len - 2); //one, three, two
+            stack.swap(len - 3, len - 1); //two, three, one
+        }
+        Word::Over => {
+            let len = stack.len();
+            if len < 2 {
+                return underflow_err();
+            }
+            stack.push(stack[len - 2]);
+        }
+        Word::Nip => {
+            let len = stack.len();
+            if len < 2 {
+                return underflow_err();
+            }
+            stack.swap_remove(len - 2);
+        }
+        Word::Tuck => {
+            let len = stack.len();
+            if len < 2 {
+                return underflow_err();
+            }
+            stack.insert(len - 2, stack[len - 1]);
+        }
+        Word::Pick => {
+            if stack.is_empty() {
+                return underflow_err();
+            }
+
+            let depth = stack.pop().unwrap();
+            let len = stack.len();
+            if depth < 0 || depth as usize >= len {
+                return underflow_err();
+            }
+            stack.push(stack[len - 1 - depth as usize]);
+        }
+        Word::Roll => {
+            if stack.is_empty() {
+                return underflow_err();
+            }
+
+            let depth = stack.pop().unwrap();
+            let len = stack.len();
+            if depth < 0 || depth as usize >= len {
+                return underflow_err();
+            }
+            stack[len - 1 - depth as usize..].rotate_left(1);
+        }
+        Word::QuestionDup => {
+            if let Some(one) = stack.last() {
+                if *one != 0 {
+                    stack.push(*one);
+                }
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Depth => {
+            stack.push(stack.len() as i64);
+        }
+        Word::TwoDup => {
+            let len = stack.len();
+            if len < 2 {
+                return underflow_err();
+            }
+            stack.extend_from_within(len - 2..);
+        }
+        Word::TwoDrop => {
+            let len = stack.len();
+            if len < 2 {
+                return underflow_err();
+            }
+            stack.truncate(len - 2);
+        }
+        Word::TwoSwap => {
+            let len = stack.len();
+            if len < 4 {
+                return underflow_err();
+            }
+            stack[len - 4..].rotate_left(2);
+        }
+        Word::TwoOver => {
+            let len = stack.len();
+            if len < 4 {
+                return underflow_err();
+            }
+            stack.extend_from_within(len - 4..len - 2);
+        }
+        Word::TwoRot => {
+            let len = stack.len();
+            if len < 6 {
+                return underflow_err();
+            }
+            stack[len - 6..].rotate_left(2);
+        }
+        Word::Exclamation => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let two = stack.pop().unwrap();
+            let one = stack.pop().unwrap();
+
+            state.memory.store_cell(two, one)?;
+        }
+        Word::I => {
+            if let Some(last) = state.loop_control_stack.last() {
+                stack.push(last.index)
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::J => {
+            if state.loop_control_stack.len() < 2 {
+                return underflow_err();
+            }
+            stack.push(state.loop_control_stack[state.loop_control_stack.len() - 2].index);
+        }
+        Word::Do => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let two = stack.pop().unwrap();
+            let one = stack.pop().unwrap();
+
+            let frame = LoopControlStackFrame {
+                index: two,
+                limit: one,
+                loop_start: index + 1,
+            };
+
+            state.loop_control_stack.push(frame);
+        }
+        Word::Then => {
+            // only marks where the if ends for the jump targets
+        }
+        Word::Unloop => {
+            if state.loop_control_stack.pop().is_none() {
+                return underflow_err();
+            }
+        }
+        Word::Case => {
+            // only marks the start of the case for the offsets
+        }
+        Word::EndCase => {
+            // no of matched, so the selector is still on the stack
+            if stack.pop().is_none() {
+                return underflow_err();
+            }
+        }
+        Word::Reset => {
+            //don't do a ton at this point, will be useful later
+            stack.clear();
+            for name in state.dictionary.hide_data_words() {
+                parsing::break_inlining(name, state);
+            }
+            state.loop_control_stack.clear();
+            state.locals.clear();
+            state.locals_frames.clear();
+        }
+        Word::OnePlus => {
+            let len = stack.len();
+            if len >= 1 {
+                stack[len - 1] += 1
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Word(raw_word) => {
+            if let Some(entry_index) = state.dictionary.find_index(raw_word) {
+                return run_entry(stack, state, index, entry_index, output);
+            }
+
+            return Err("Unrecognized word ".to_string() + &*format!("{word:?}"));
+        }
+        Word::Call(symbol) => {
+            if let Some(entry_index) = state.dictionary.resolve(*symbol) {
+                return run_entry(stack, state, index, entry_index, output);
+            }
+
+            let name = state.dictionary.symbol_name(*symbol);
+            return Err(format!(
+                "Unrecognized word {:?}",
+                Word::Word(name.to_string())
+            ));
+        }
+        Word::Tick(name) => match state.dictionary.find_index(name) {
+            Some(entry_index) => stack.push(entry_index as i64),
+            None => return Err(format!("Unrecognized word {name}")),
+        },
+        Word::Quotation(body) => {
+            let xt = match state.quotations.get(body) {
+                Some(xt) => *xt,
+                None => {
+                    let xt = state.dictionary.define_hidden(
+                        "quotation",
+                        EntryKind::Colon(DefinedWord {
+                            words: Rc::new(body.clone()),
+                            original_words: Rc::new(body.clone()),
+                            has_been_inlined: false,
+                            inline_count: 0,
+                            depends_on: HashSet::new(),
+                            always_inline: false,
+                            effect: None,
+                        }),
+                        state.location.clone(),
+                    );
+                    state.quotations.insert(body.clone(), xt);
+                    xt
+                }
+            };
+            stack.push(xt as i64);
+        }
+        Word::Execute => {
+            if let Some(xt) = stack.pop() {
+                if xt < 0 || state.dictionary.get(xt as usize).is_none() {
+                    return Err(format!("Invalid execution token {xt}"));
+                }
+                return run_entry(stack, state, index, xt as usize, output);
+            } else {
+                return underflow_err();
+            }
+        }
+
+        //optimizations
+        Word::DoubleRot => {
+            let len = stack.len();
+            if len < 3 {
+                return underflow_err();
+            }
+
+            stack.swap(len - 1, len - 2); //one, three, two
+            stack.swap(len - 3, len - 2); //three, one, two
+        }
+        Word::EqZero => {
+            if let Some(one) = stack.last() {
+                let len: usize = stack.len();
+                if *one == 0 {
+                    stack[len - 1] = 1
+                } else {
+                    stack[len - 1] = 0
+                }
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::DupModConst(n) => {
+            if stack.is_empty() {
+                return underflow_err();
+            }
+
+            let one = stack.last().unwrap();
+
+            stack.push(one % n);
+        }
+        Word::DotQuote(w) => {
+            let result = stack.pop();
+            if let Some(val) = result {
+                //use optimized integer writing
+                state.internal_buffer.clear();
+                itoap::write_to_vec(&mut state.internal_buffer, val);
+
+                output
+                    .write_all(&state.internal_buffer)
+                    .expect("Could not write value");
+                output.write_all(w.as_ref()).expect("Could not write quote");
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::IPlusConst(constant) => {
+            if let Some(last) = state.loop_control_stack.last() {
+                stack.push(last.index + *constant)
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Value(_)
+        | Word::TwoVariable(_)
+        | Word::TwoConstant(_)
+        | Word::TwoValue(_)
+        | Word::Buffer(_)
+        | Word::To(_)
+        | Word::PlusTo(_)
+        | Word::Create(_)
+        | Word::BeginStructure(_)
+        | Word::PlusField(_)
+        | Word::FieldColon(_)
+        | Word::CFieldColon(_)
+        | Word::FFieldColon(_)
+        | Word::EndStructure => return run_defining_word(stack, state, word),
+        Word::Comma => {
+            if let Some(val) = stack.pop() {
+                let addr = state.memory.allot(CELL_SIZE as usize);
+                state.memory.store_cell(addr, val)?;
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Allot => {
+            if let Some(len) = stack.pop() {
+                if len < 0 {
+                    return Err(format!("Invalid allot size {len}"));
+                }
+                state.memory.allot(len as usize);
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Defined(name) => {
+            let defined = state.dictionary.find(name).is_some();
+            stack.push(defined as i64);
+        }
+        Word::Undefined(name) => {
+            let defined = state.dictionary.find(name).is_some();
+            stack.push(!defined as i64);
+        }
+        Word::Here => {
+            stack.push(state.memory.here());
+        }
+        Word::Allocate | Word::Free | Word::Resize | Word::Move | Word::Fill | Word::Erase => {
+            return run_memory_word(stack, state, word)
+        }
+        Word::Compare
+        | Word::Search
+        | Word::SlashString
+        | Word::MinusTrailing
+        | Word::Blank
+        | Word::CMove
+        | Word::CMoveUp
+        | Word::Replaces
+        | Word::Substitute => return run_string_word(stack, state, word),
+        Word::Key | Word::KeyQuestion | Word::Ekey | Word::Accept | Word::AtXy | Word::Page => {
+            return run_terminal_word(stack, state, word, output)
+        }
+        Word::Ms | Word::TimeAndDate | Word::Utime | Word::UtimeNs | Word::Ticks => {
+            return run_time_word(stack, state, word, output)
+        }
+        Word::Block
+        | Word::BlockBuffer
+        | Word::Update
+        | Word::SaveBuffers
+        | Word::Flush
+        | Word::Load
+        | Word::Thru
+        | Word::List
+        | Word::Scr => return run_block_word(stack, state, word, output),
+        Word::PlacedString(addr, literal) => {
+            stack.push(*addr);
+            stack.push(literal.len() as i64);
+        }
+        Word::Emit => {
+            if let Some(byte) = stack.pop() {
+                output
+                    .write_all(&[byte as u8])
+                    .expect("Could not write char");
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Type => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let len = stack.pop().unwrap();
+            let addr = stack.pop().unwrap();
+
+            let bytes = state.memory.bytes(addr, len as usize)?;
+            output.write_all(bytes).expect("Could not write string");
+        }
+        Word::Evaluate => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let len = stack.pop().unwrap();
+            let addr = stack.pop().unwrap();
+
+            let source =
+                String::from_utf8_lossy(state.memory.bytes(addr, len as usize)?).into_owned();
+            if let Err(e) = interpret_source(stack, state, InputSource::Evaluate, &source, output) {
+                return Err(e.to_string());
+            }
+        }
+        Word::Locals(args, uninitialized) => {
+            let len = stack.len();
+            if len < *args {
+                return underflow_err();
+            }
+
+            // the first declared local gets the deepest stack item
+            state.locals_frames.push(state.locals.len());
+            state.locals.extend(stack.drain(len - args..));
+            state.locals.resize(state.locals.len() + uninitialized, 0);
+        }
+        Word::LocalFetch(index) => {
+            let base = *state.locals_frames.last().unwrap();
+            stack.push(state.locals[base + index]);
+        }
+        Word::LocalStore(index) => {
+            let base = *state.locals_frames.last().unwrap();
+            if let Some(val) = stack.pop() {
+                state.locals[base + index] = val;
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::EndLocals => {
+            let base = state.locals_frames.pop().unwrap();
+            state.locals.truncate(base);
+        }
+        Word::SourceId => match state.input_sources.last() {
+            None => stack.push(0),
+            Some(InputSource::Evaluate | InputSource::Prelude) => stack.push(-1),
+            // blocks don't have a source id, they're identified by blk instead
+            Some(InputSource::Block(_)) => stack.push(0),
+        },
+
+        _ => {
+            return Err("Can't handle ".to_string() + &*format!("{word:?}"));
+        }
+    }
+
+    Ok(())
+}
+
+// the words below are kept out of run_word, since every arm there adds to the native stack
+// used by each level of nested execute, evaluate and load
+
+// words which define a name, or change what one holds
+fn run_defining_word(stack: &mut Vec<i64>, state: &mut State, word: &Word) -> Result<(), String> {
+    // run through execute there's no name after it on the line
+    if let Word::Value(name)
+    | Word::TwoVariable(name)
+    | Word::TwoConstant(name)
+    | Word::TwoValue(name)
+    | Word::Buffer(name)
+    | Word::To(name)
+    | Word::PlusTo(name)
+    | Word::Create(name)
+    | Word::BeginStructure(name)
+    | Word::PlusField(name)
+    | Word::FieldColon(name)
+    | Word::CFieldColon(name)
+    | Word::FFieldColon(name) = word
+    {
+        if name.is_empty() {
+            return Err("Expected a name".to_string());
+        }
+    }
+
+    match word {
+        Word::Value(name) => {
+            if let Some(val) = stack.pop() {
+                let addr = state.memory.allot(CELL_SIZE as usize);
+                state.memory.store_cell(addr, val)?;
+                define(state, name, EntryKind::Value(addr));
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::TwoVariable(name) => {
+            let addr = state.memory.allot(2 * CELL_SIZE as usize);
+            define(state, name, EntryKind::TwoVariable(addr));
+        }
+        Word::TwoConstant(name) => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let two = stack.pop().unwrap();
+            let one = stack.pop().unwrap();
+            define(state, name, EntryKind::TwoConstant(one, two));
+        }
+        Word::TwoValue(name) => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let two = stack.pop().unwrap();
+            let one = stack.pop().unwrap();
+
+            let addr = state.memory.allot(2 * CELL_SIZE as usize);
+            state.memory.store_cell(addr, two)?;
+            state.memory.store_cell(addr + CELL_SIZE, one)?;
+            define(state, name, EntryKind::TwoValue(addr));
+        }
+        Word::Buffer(name) => {
+            if let Some(len) = stack.pop() {
+                if len < 0 {
+                    return Err(format!("Invalid buffer size {len}"));
+                }
+                let addr = state.memory.allot(len as usize);
+                define(state, name, EntryKind::Buffer(addr));
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::To(name) => match state.dictionary.find(name).map(|e| &e.kind) {
+            Some(&EntryKind::Value(addr)) => {
+                if let Some(val) = stack.pop() {
+                    state.memory.store_cell(addr, val)?;
+                } else {
+                    return underflow_err();
+                }
+            }
+            Some(&EntryKind::TwoValue(addr)) => {
+                if stack.len() < 2 {
+                    return underflow_err();
+                }
+
+                let two = stack.pop().unwrap();
+                let one = stack.pop().unwrap();
+                state.memory.store_cell(addr, two)?;
+                state.memory.store_cell(addr + CELL_SIZE, one)?;
+            }
+            _ => return Err(format!("{name} is not a value")),
+        },
+        Word::PlusTo(name) => match state.dictionary.find(name).map(|e| &e.kind) {
+            Some(&EntryKind::Value(addr)) => {
+                if let Some(val) = stack.pop() {
+                    let current = state.memory.fetch_cell(addr)?;
+                    state.memory.store_cell(addr, current + val)?;
+                } else {
+                    return underflow_err();
+                }
+            }
+            _ => return Err(format!("{name} is not a value")),
+        },
+        Word::Create(name) => {
+            let addr = state.memory.here();
+            define(state, name, EntryKind::Created(addr));
+        }
+        Word::BeginStructure(name) => {
+            // the size isn't known until end-structure, which gets the entry back from the stack
+            let index = define(state, name, EntryKind::Constant(0));
+            stack.push(index as i64);
+            stack.push(0);
+        }
+        Word::PlusField(name)
+        | Word::FieldColon(name)
+        | Word::CFieldColon(name)
+        | Word::FFieldColon(name) => {
+            let size = match word {
+                Word::PlusField(_) => match stack.pop() {
+                    Some(size) => size,
+                    None => return underflow_err(),
+                },
+                Word::CFieldColon(_) => 1,
+                _ => CELL_SIZE,
+            };
+            if let Some(offset) = stack.pop() {
+                // cell and float fields are cell aligned
+                let offset = match word {
+                    Word::FieldColon(_) | Word::FFieldColon(_) => {
+                        (offset + CELL_SIZE - 1) / CELL_SIZE * CELL_SIZE
+                    }
+                    _ => offset,
+                };
+                define(state, name, EntryKind::Field(offset));
+                stack.push(offset + size);
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::EndStructure => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let size = stack.pop().unwrap();
+            let index = stack.pop().unwrap();
+
+            let name = match state.dictionary.get(index as usize) {
+                Some(entry) if index >= 0 && matches!(entry.kind, EntryKind::Constant(0)) => {
+                    entry.name.clone()
+                }
+                _ => return Err("end-structure without begin-structure".to_string()),
+            };
+            state
+                .dictionary
+                .set_kind(index as usize, EntryKind::Constant(size));
+            parsing::break_inlining(name, state);
+        }
+        _ => unreachable!("{word:?} is not a defining word"),
+    }
+
+    Ok(())
+}
+
+// the memory allocation word set and its companions
+fn run_memory_word(stack: &mut Vec<i64>, state: &mut State, word: &Word) -> Result<(), String> {
+    match word {
+        Word::Allocate => {
+            if let Some(len) = stack.pop() {
+                match state.memory.allocate(len) {
+                    Some(addr) => {
+                        stack.push(addr);
+                        stack.push(0);
+                    }
+                    None => {
+                        stack.push(0);
+                        stack.push(ALLOCATE_IOR);
+                    }
+                }
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Free => {
+            if let Some(addr) = stack.pop() {
+                let ior = state.memory.free(addr)?;
+                stack.push(ior);
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Resize => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let len = stack.pop().unwrap();
+            let addr = stack.pop().unwrap();
+
+            match state.memory.resize(addr, len)? {
+                Some(new_addr) => {
+                    stack.push(new_addr);
+                    stack.push(0);
+                }
+                None => {
+                    // the original block is still valid
+                    stack.push(addr);
+                    stack.push(RESIZE_IOR);
+                }
+            }
+        }
+        Word::Move => {
+            if stack.len() < 3 {
+                return underflow_err();
+            }
+
+            let len = stack.pop().unwrap();
+            let to = stack.pop().unwrap();
+            let from = stack.pop().unwrap();
+
+            if len > 0 {
+                state.memory.copy(from, to, len as usize)?;
+            }
+        }
+        Word::Fill => {
+            if stack.len() < 3 {
+                return underflow_err();
+            }
+
+            let byte = stack.pop().unwrap();
+            let len = stack.pop().unwrap();
+            let addr = stack.pop().unwrap();
+
+            if len > 0 {
+                state.memory.bytes_mut(addr, len as usize)?.fill(byte as u8);
+            }
+        }
+        Word::Erase => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let len = stack.pop().unwrap();
+            let addr = stack.pop().unwrap();
+
+            if len > 0 {
+                state.memory.bytes_mut(addr, len as usize)?.fill(0);
+            }
+        }
+        _ => unreachable!("{word:?} is not a memory word"),
+    }
+
+    Ok(())
+}
+
+// the string word set
+fn run_string_word(stack: &mut Vec<i64>, state: &mut State, word: &Word) -> Result<(), String> {
+    match word {
+        Word::Compare => {
+            if stack.len() < 4 {
+                return underflow_err();
+            }
+
+            let len2 = stack.pop().unwrap();
+            let addr2 = stack.pop().unwrap();
+            let len1 = stack.pop().unwrap();
+            let addr1 = stack.pop().unwrap();
+
+            let one = state.memory.bytes(addr1, len1.max(0) as usize)?;
+            let two = state.memory.bytes(addr2, len2.max(0) as usize)?;
+            stack.push(one.cmp(two) as i64);
+        }
+        Word::Search => {
+            if stack.len() < 4 {
+                return underflow_err();
+            }
+
+            let len2 = stack.pop().unwrap();
+            let addr2 = stack.pop().unwrap();
+            let len1 = stack.pop().unwrap();
+            let addr1 = stack.pop().unwrap();
+
+            let haystack = state.memory.bytes(addr1, len1.max(0) as usize)?;
+            let needle = state.memory.bytes(addr2, len2.max(0) as usize)?;
+            let found = if needle.is_empty() {
+                Some(0)
+            } else {
+                haystack
+                    .windows(needle.len())
+                    .position(|window| window == needle)
+            };
+            match found {
+                Some(offset) => {
+                    stack.push(addr1 + offset as i64);
+                    stack.push(len1 - offset as i64);
+                    stack.push(1);
+                }
+                None => {
+                    stack.push(addr1);
+                    stack.push(len1);
+                    stack.push(0);
+                }
+            }
+        }
+        Word::SlashString => {
+            let len = stack.len();
+            if len < 3 {
+                return underflow_err();
+            }
+
+            let n = stack.pop().unwrap();
+            stack[len - 3] += n;
+            stack[len - 2] -= n;
+        }
+        Word::MinusTrailing => {
+            let len = stack.len();
+            if len < 2 {
+                return underflow_err();
+            }
+
+            let bytes = state
+                .memory
+                .bytes(stack[len - 2], stack[len - 1].max(0) as usize)?;
+            let trailing = bytes.iter().rev().take_while(|byte| **byte == b' ').count();
+            stack[len - 1] = (bytes.len() - trailing) as i64;
+        }
+        Word::Blank => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let len = stack.pop().unwrap();
+            let addr = stack.pop().unwrap();
+
+            if len > 0 {
+                state.memory.bytes_mut(addr, len as usize)?.fill(b' ');
+            }
+        }
+        Word::CMove | Word::CMoveUp => {
+            if stack.len() < 3 {
+                return underflow_err();
+            }
+
+            let len = stack.pop().unwrap();
+            let to = stack.pop().unwrap();
+            let from = stack.pop().unwrap();
+
+            if len > 0 {
+                let ascending = *word == Word::CMove;
+                state
+                    .memory
+                    .copy_bytewise(from, to, len as usize, ascending)?;
+            }
+        }
+        Word::Replaces => {
+            if stack.len() < 4 {
+                return underflow_err();
+            }
+
+            let name_len = stack.pop().unwrap();
+            let name_addr = stack.pop().unwrap();
+            let len = stack.pop().unwrap();
+            let addr = stack.pop().unwrap();
+
+            let name = state.memory.bytes(name_addr, name_len.max(0) as usize)?;
+            let name = String::from_utf8_lossy(name).to_lowercase();
+            let replacement = state.memory.bytes(addr, len.max(0) as usize)?.to_vec();
+            state.substitutions.insert(name, replacement);
+        }
+        Word::Substitute => {
+            if stack.len() < 4 {
+                return underflow_err();
+            }
+
+            let buffer_len = stack.pop().unwrap();
+            let buffer_addr = stack.pop().unwrap();
+            let len = stack.pop().unwrap();
+            let addr = stack.pop().unwrap();
+
+            let (result, count) = substitute(state, addr, len)?;
+            let written = result.len().min(buffer_len.max(0) as usize);
+            if written > 0 {
+                state
+                    .memory
+                    .bytes_mut(buffer_addr, written)?
+                    .copy_from_slice(&result[..written]);
+            }
+            stack.push(buffer_addr);
+            stack.push(written as i64);
+            if written < result.len() {
+                stack.push(SUBSTITUTE_IOR);
+            } else {
+                stack.push(count);
+            }
+        }
+        _ => unreachable!("{word:?} is not a string word"),
+    }
+
+    Ok(())
+}
+
+// the facility word set
+fn run_terminal_word(
+    stack: &mut Vec<i64>,
+    state: &mut State,
+    word: &Word,
+    output: &mut dyn Write,
+) -> Result<(), String> {
+    match word {
+        Word::Key => {
+            // anything already printed should be seen before waiting on a key
+            output.flush().expect("Could not flush output");
+            let key = state.terminal.key()?;
+            stack.push(key as i64);
+        }
+        Word::KeyQuestion => {
+            let ready = state.terminal.key_ready()?;
+            stack.push(ready as i64);
+        }
+        Word::Ekey => {
+            output.flush().expect("Could not flush output");
+            let event = state.terminal.ekey()?;
+            stack.push(event);
+        }
+        Word::Accept => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let max = stack.pop().unwrap();
+            let addr = stack.pop().unwrap();
+
+            output.flush().expect("Could not flush output");
+            let line = state.terminal.accept(max.max(0) as usize, output)?;
+            if !line.is_empty() {
+                state
+                    .memory
+                    .bytes_mut(addr, line.len())?
+                    .copy_from_slice(&line);
+            }
+            stack.push(line.len() as i64);
+        }
+        Word::AtXy => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let row = stack.pop().unwrap();
+            let column = stack.pop().unwrap();
+
+            state.terminal.at_xy(column, row, output);
+        }
+        Word::Page => {
+            state.terminal.page(output);
+        }
+        _ => unreachable!("{word:?} is not a terminal word"),
+    }
+
+    Ok(())
+}
+
+// time and delays, from state.clock
+fn run_time_word(
+    stack: &mut Vec<i64>,
+    state: &mut State,
+    word: &Word,
+    output: &mut dyn Write,
+) -> Result<(), String> {
+    match word {
+        Word::Ms => {
+            if let Some(ms) = stack.pop() {
+                // whatever was printed before the wait should show up before it
+                output.flush().expect("Could not flush output");
+                state.clock.sleep_ms(ms.max(0) as u64);
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::TimeAndDate => {
+            stack.extend(state.clock.time_and_date());
+        }
+        Word::Utime => {
+            stack.push((state.clock.now_ns() / 1000) as i64);
+        }
+        Word::UtimeNs => {
+            stack.push(state.clock.now_ns() as i64);
+        }
+        Word::Ticks => {
+            stack.push(state.clock.ticks() as i64);
+        }
+        _ => unreachable!("{word:?} is not a time word"),
+    }
+
+    Ok(())
+}
+
+// the block word set
+fn run_block_word(
+    stack: &mut Vec<i64>,
+    state: &mut State,
+    word: &Word,
+    output: &mut dyn Write,
+) -> Result<(), String> {
+    match word {
+        Word::Block | Word::BlockBuffer => {
+            if let Some(block) = stack.pop() {
+                let addr = if *word == Word::Block {
+                    state.blocks.block(&mut state.memory, block)?
+                } else {
+                    state.blocks.buffer(&mut state.memory, block)?
+                };
+                stack.push(addr);
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Update => {
+            state.blocks.update()?;
+        }
+        Word::SaveBuffers => {
+            state.blocks.save_buffers(&state.memory)?;
+        }
+        Word::Flush => {
+            state.blocks.flush(&state.memory)?;
+        }
+        Word::Load => {
+            if let Some(block) = stack.pop() {
+                load(stack, state, block, output)?;
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Thru => {
+            if stack.len() < 2 {
+                return underflow_err();
+            }
+
+            let last = stack.pop().unwrap();
+            let first = stack.pop().unwrap();
+
+            for block in first..=last {
+                load(stack, state, block, output)?;
+            }
+        }
+        Word::List => {
+            if let Some(block) = stack.pop() {
+                let text = block_text(state, block)?;
+                let scr = state.blocks.scr(&mut state.memory)?;
+                state.memory.store_cell(scr, block)?;
+
+                writeln!(output, "Screen {block}").expect("Could not write block");
+                for (line_number, line) in text.split('\n').take(BLOCK_LINES).enumerate() {
+                    writeln!(output, "{line_number:2} {line}").expect("Could not write block");
+                }
+            } else {
+                return underflow_err();
+            }
+        }
+        Word::Scr => {
+            stack.push(state.blocks.scr(&mut state.memory)?);
+        }
+        _ => unreachable!("{word:?} is not a block word"),
+    }
+
+    Ok(())
+}
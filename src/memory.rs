@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+
+// this file is responsible for the byte addressed data space that strings and variables live in,
+// and the heap that allocate hands out blocks from
+
+// addresses handed out to forth code start here, so small numbers are never mistaken for valid addresses
+pub(crate) const DATA_SPACE_BASE: i64 = 0x10000;
+// far enough above data space that the two can never meet
+pub(crate) const HEAP_BASE: i64 = 0x1_0000_0000;
+pub(crate) const CELL_SIZE: i64 = 8;
+// blocks start on this boundary, with at least this much unused space between them
+const HEAP_ALIGN: i64 = 16;
+
+// iors from the memory allocation word set, 0 is success
+pub(crate) const ALLOCATE_IOR: i64 = -59;
+pub(crate) const FREE_IOR: i64 = -60;
+pub(crate) const RESIZE_IOR: i64 = -61;
+
+#[derive(Debug, Clone)]
+struct HeapBlock {
+    data: Vec<u8>,
+    // freed blocks are only kept around in debug mode
+    freed: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Memory {
+    data: Vec<u8>,
+    // keyed by the address of the first byte
+    heap: BTreeMap<i64, HeapBlock>,
+    // addresses are never reused, so a stale address can't silently point into a newer block
+    heap_next: i64,
+    // keep freed blocks so double frees and use after free are errors
+    debug: bool,
+}
+
+fn invalid_address_err(addr: i64, len: usize) -> String {
+    format!("Invalid memory access at {addr} (length {len})")
+}
+
+impl Memory {
+    pub(crate) fn new() -> Memory {
+        Memory {
+            data: Vec::with_capacity(1024),
+            heap: BTreeMap::new(),
+            heap_next: HEAP_BASE,
+            debug: false,
+        }
+    }
+
+    pub(crate) fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    pub(crate) fn debug(&self) -> bool {
+        self.debug
+    }
+
+    // the next free address in data space
+    pub(crate) fn here(&self) -> i64 {
+        DATA_SPACE_BASE + self.data.len() as i64
+    }
+
+    // reserve `len` zeroed bytes and return the address of the first one
+    pub(crate) fn allot(&mut self, len: usize) -> i64 {
+        let addr = self.here();
+        self.data.resize(self.data.len() + len, 0);
+        addr
+    }
+
+    // copy bytes into freshly allotted data space, returning the address
+    pub(crate) fn store_bytes(&mut self, bytes: &[u8]) -> i64 {
+        let addr = self.here();
+        self.data.extend_from_slice(bytes);
+        addr
+    }
+
+    // the block an address points into, and the offset into it
+    fn heap_block(&self, addr: i64, len: usize) -> Result<(i64, usize), String> {
+        match self.heap.range(..=addr).next_back() {
+            Some((start, block)) if block.freed && addr < start + block.data.len() as i64 => {
+                Err(format!("Use after free at {addr}"))
+            }
+            Some((_, block)) if block.freed => Err(invalid_address_err(addr, len)),
+            Some((start, block)) => {
+                let offset = (addr - start) as usize;
+                match offset.checked_add(len) {
+                    Some(end) if end <= block.data.len() => Ok((*start, offset)),
+                    _ => Err(invalid_address_err(addr, len)),
+                }
+            }
+            None => Err(invalid_address_err(addr, len)),
+        }
+    }
+
+    // returns the address of `len` zeroed bytes, or none if that much can't be allocated
+    pub(crate) fn allocate(&mut self, len: i64) -> Option<i64> {
+        if len < 0 {
+            return None;
+        }
+        let addr = self.heap_next;
+        let end = addr.checked_add(len)?.checked_add(HEAP_ALIGN)?;
+        let mut data = Vec::new();
+        data.try_reserve_exact(len as usize).ok()?;
+        data.resize(len as usize, 0);
+        self.heap.insert(addr, HeapBlock { data, freed: false });
+        self.heap_next = end - end % HEAP_ALIGN + HEAP_ALIGN;
+        Some(addr)
+    }
+
+    // the ior for freeing the block starting at `addr`
+    pub(crate) fn free(&mut self, addr: i64) -> Result<i64, String> {
+        match self.heap.get_mut(&addr) {
+            Some(block) if block.freed => Err(format!("Double free at {addr}")),
+            Some(block) if self.debug => {
+                // the data is kept so accesses anywhere inside the block are caught
+                block.freed = true;
+                Ok(0)
+            }
+            Some(_) => {
+                self.heap.remove(&addr);
+                Ok(0)
+            }
+            None => Ok(FREE_IOR),
+        }
+    }
+
+    // moves the block at `addr` to a new block of `len` bytes, keeping what fits
+    // on failure the original block is left alone
+    pub(crate) fn resize(&mut self, addr: i64, len: i64) -> Result<Option<i64>, String> {
+        let old_len = match self.heap.get(&addr) {
+            Some(block) if block.freed => return Err(format!("Use after free at {addr}")),
+            Some(block) => block.data.len(),
+            None => return Ok(None),
+        };
+        let Some(new_addr) = self.allocate(len) else {
+            return Ok(None);
+        };
+        let kept = old_len.min(len as usize);
+        let old = self.heap[&addr].data[..kept].to_vec();
+        self.heap.get_mut(&new_addr).unwrap().data[..kept].copy_from_slice(&old);
+        self.free(addr)?;
+        Ok(Some(new_addr))
+    }
+
+    fn range(&self, addr: i64, len: usize) -> Result<std::ops::Range<usize>, String> {
+        if addr < DATA_SPACE_BASE {
+            return Err(invalid_address_err(addr, len));
+        }
+        let start = (addr - DATA_SPACE_BASE) as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= self.data.len() => Ok(start..end),
+            _ => Err(invalid_address_err(addr, len)),
+        }
+    }
+
+    pub(crate) fn bytes(&self, addr: i64, len: usize) -> Result<&[u8], String> {
+        if addr >= HEAP_BASE {
+            let (start, offset) = self.heap_block(addr, len)?;
+            return Ok(&self.heap[&start].data[offset..offset + len]);
+        }
+        let range = self.range(addr, len)?;
+        Ok(&self.data[range])
+    }
+
+    pub(crate) fn bytes_mut(&mut self, addr: i64, len: usize) -> Result<&mut [u8], String> {
+        if addr >= HEAP_BASE {
+            let (start, offset) = self.heap_block(addr, len)?;
+            let block = self.heap.get_mut(&start).unwrap();
+            return Ok(&mut block.data[offset..offset + len]);
+        }
+        let range = self.range(addr, len)?;
+        Ok(&mut self.data[range])
+    }
+
+    pub(crate) fn fetch_cell(&self, addr: i64) -> Result<i64, String> {
+        let bytes = self.bytes(addr, CELL_SIZE as usize)?;
+        Ok(i64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub(crate) fn store_cell(&mut self, addr: i64, val: i64) -> Result<(), String> {
+        let bytes = self.bytes_mut(addr, CELL_SIZE as usize)?;
+        bytes.copy_from_slice(&val.to_le_bytes());
+        Ok(())
+    }
+
+    // copies one byte at a time, so an overlapping destination sees bytes already copied
+    // lowest address first when ascending, like cmove, otherwise highest first like cmove>
+    pub(crate) fn copy_bytewise(
+        &mut self,
+        from: i64,
+        to: i64,
+        len: usize,
+        ascending: bool,
+    ) -> Result<(), String> {
+        // check the whole of both ranges up front so nothing is half copied
+        self.bytes(from, len)?;
+        self.bytes_mut(to, len)?;
+        for n in 0..len as i64 {
+            let offset = if ascending { n } else { len as i64 - 1 - n };
+            let byte = self.bytes(from + offset, 1)?[0];
+            self.bytes_mut(to + offset, 1)?[0] = byte;
+        }
+        Ok(())
+    }
+
+    // copies correctly even when the two regions overlap
+    pub(crate) fn copy(&mut self, from: i64, to: i64, len: usize) -> Result<(), String> {
+        let source = self.bytes(from, len)?.to_vec();
+        self.bytes_mut(to, len)?.copy_from_slice(&source);
+        Ok(())
+    }
+}
@@ -0,0 +1,549 @@
+use crate::parsing::Word;
+use ahash::{HashSet, HashSetExt};
+use std::cell::RefCell;
+
+// this file is responsible for the peephole optimizer, which rewrites short runs of words into
+// cheaper ones. each rewrite is a rule in RULES, applied wherever its pattern matches until none do
+
+// what one word of a rule's pattern matches
+enum Pattern {
+    // exactly this word
+    Is(Word),
+    // any number, captured
+    Number,
+    // any ." text, captured
+    Quote,
+    // any number printed with text after it, the text captured
+    DotQuote,
+    // any if, its target captured
+    If,
+    // a quotation without an exit in it, which would return from the enclosing word once spliced
+    // in, its body captured
+    Quotation,
+}
+
+// what a pattern word captured, in pattern order
+enum Capture {
+    Number(i64),
+    Text(String),
+    Code(Vec<Word>),
+}
+
+impl Capture {
+    fn number(&self) -> i64 {
+        match self {
+            Capture::Number(n) => *n,
+            _ => unreachable!(),
+        }
+    }
+
+    fn text(&self) -> &str {
+        match self {
+            Capture::Text(text) => text,
+            _ => unreachable!(),
+        }
+    }
+
+    fn code(&self) -> Vec<Word> {
+        match self {
+            Capture::Code(code) => code.clone(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+struct Rule {
+    name: &'static str,
+    pattern: &'static [Pattern],
+    // what the matched words become given the captures, none if the rule turns out not to apply
+    replacement: fn(&[Capture]) -> Option<Vec<Word>>,
+    // the replacement as --list-rules shows it, captures are a, b, ... in pattern order
+    shown_as: &'static str,
+}
+
+use Pattern::Is;
+
+// tried in order at each word, so a rule must come before any shorter one matching its start
+static RULES: &[Rule] = &[
+    Rule {
+        name: "execute-quotation",
+        pattern: &[Pattern::Quotation, Is(Word::Execute)],
+        replacement: |c| Some(c[0].code()),
+        shown_as: "a",
+    },
+    Rule {
+        name: "double-rot",
+        pattern: &[Is(Word::Rot), Is(Word::Rot)],
+        replacement: |_| Some(vec![Word::DoubleRot]),
+        shown_as: "DoubleRot",
+    },
+    // `swap dup rot rot` is the classic definition of over
+    Rule {
+        name: "over",
+        pattern: &[Is(Word::Swap), Is(Word::Dup), Is(Word::Rot), Is(Word::Rot)],
+        replacement: |_| Some(vec![Word::Over]),
+        shown_as: "Over",
+    },
+    Rule {
+        name: "over-after-double-rot",
+        pattern: &[Is(Word::Swap), Is(Word::Dup), Is(Word::DoubleRot)],
+        replacement: |_| Some(vec![Word::Over]),
+        shown_as: "Over",
+    },
+    Rule {
+        name: "nip",
+        pattern: &[Is(Word::Swap), Is(Word::Drop)],
+        replacement: |_| Some(vec![Word::Nip]),
+        shown_as: "Nip",
+    },
+    Rule {
+        name: "tuck",
+        pattern: &[Is(Word::Swap), Is(Word::Over)],
+        replacement: |_| Some(vec![Word::Tuck]),
+        shown_as: "Tuck",
+    },
+    Rule {
+        name: "two-dup",
+        pattern: &[Is(Word::Over), Is(Word::Over)],
+        replacement: |_| Some(vec![Word::TwoDup]),
+        shown_as: "TwoDup",
+    },
+    Rule {
+        name: "two-drop",
+        pattern: &[Is(Word::Drop), Is(Word::Drop)],
+        replacement: |_| Some(vec![Word::TwoDrop]),
+        shown_as: "TwoDrop",
+    },
+    Rule {
+        name: "not-if",
+        pattern: &[Is(Word::Number(0)), Is(Word::Equal), Pattern::If],
+        replacement: |c| Some(vec![Word::NotIf(c[0].number() as usize)]),
+        shown_as: "NotIf(a)",
+    },
+    Rule {
+        name: "eq-zero",
+        pattern: &[Is(Word::Number(0)), Is(Word::Equal)],
+        replacement: |_| Some(vec![Word::EqZero]),
+        shown_as: "EqZero",
+    },
+    Rule {
+        name: "zero-slash-string",
+        pattern: &[Is(Word::Number(0)), Is(Word::SlashString)],
+        replacement: |_| Some(vec![]),
+        shown_as: "nothing",
+    },
+    Rule {
+        name: "zero-plus",
+        pattern: &[Is(Word::Number(0)), Is(Word::Plus)],
+        replacement: |_| Some(vec![]),
+        shown_as: "nothing",
+    },
+    // like a field offset added to a known address
+    Rule {
+        name: "fold-plus",
+        pattern: &[Pattern::Number, Pattern::Number, Is(Word::Plus)],
+        replacement: |c| {
+            Some(vec![Word::Number(
+                c[0].number().checked_add(c[1].number())?,
+            )])
+        },
+        shown_as: "Number(a + b)",
+    },
+    // the rest of the arithmetic and comparisons, as they'd run, but never folding what would
+    // overflow or divide by zero so those still happen when the code runs
+    Rule {
+        name: "fold-mult",
+        pattern: &[Pattern::Number, Pattern::Number, Is(Word::Mult)],
+        replacement: |c| {
+            Some(vec![Word::Number(
+                c[0].number().checked_mul(c[1].number())?,
+            )])
+        },
+        shown_as: "Number(a * b)",
+    },
+    Rule {
+        name: "fold-mod",
+        pattern: &[Pattern::Number, Pattern::Number, Is(Word::Mod)],
+        replacement: |c| {
+            Some(vec![Word::Number(
+                c[0].number().checked_rem(c[1].number())?,
+            )])
+        },
+        shown_as: "Number(a mod b)",
+    },
+    Rule {
+        name: "fold-mult-divide",
+        pattern: &[
+            Pattern::Number,
+            Pattern::Number,
+            Pattern::Number,
+            Is(Word::MultDivide),
+        ],
+        replacement: |c| {
+            let product = c[0].number().checked_mul(c[1].number())?;
+            Some(vec![Word::Number(product.checked_div(c[2].number())?)])
+        },
+        shown_as: "Number(a * b / c)",
+    },
+    Rule {
+        name: "fold-one-plus",
+        pattern: &[Pattern::Number, Is(Word::OnePlus)],
+        replacement: |c| Some(vec![Word::Number(c[0].number().checked_add(1)?)]),
+        shown_as: "Number(a + 1)",
+    },
+    Rule {
+        name: "fold-equal",
+        pattern: &[Pattern::Number, Pattern::Number, Is(Word::Equal)],
+        replacement: |c| Some(vec![Word::Number((c[0].number() == c[1].number()) as i64)]),
+        shown_as: "Number(a = b)",
+    },
+    Rule {
+        name: "fold-greater",
+        pattern: &[Pattern::Number, Pattern::Number, Is(Word::Greater)],
+        replacement: |c| Some(vec![Word::Number((c[0].number() > c[1].number()) as i64)]),
+        shown_as: "Number(a > b)",
+    },
+    Rule {
+        name: "fold-less",
+        pattern: &[Pattern::Number, Pattern::Number, Is(Word::Less)],
+        replacement: |c| Some(vec![Word::Number((c[0].number() < c[1].number()) as i64)]),
+        shown_as: "Number(a < b)",
+    },
+    Rule {
+        name: "fold-eq-zero",
+        pattern: &[Pattern::Number, Is(Word::EqZero)],
+        replacement: |c| Some(vec![Word::Number((c[0].number() == 0) as i64)]),
+        shown_as: "Number(a = 0)",
+    },
+    Rule {
+        name: "zero-pick",
+        pattern: &[Is(Word::Number(0)), Is(Word::Pick)],
+        replacement: |_| Some(vec![Word::Dup]),
+        shown_as: "Dup",
+    },
+    Rule {
+        name: "one-pick",
+        pattern: &[Is(Word::Number(1)), Is(Word::Pick)],
+        replacement: |_| Some(vec![Word::Over]),
+        shown_as: "Over",
+    },
+    Rule {
+        name: "zero-roll",
+        pattern: &[Is(Word::Number(0)), Is(Word::Roll)],
+        replacement: |_| Some(vec![]),
+        shown_as: "nothing",
+    },
+    Rule {
+        name: "one-roll",
+        pattern: &[Is(Word::Number(1)), Is(Word::Roll)],
+        replacement: |_| Some(vec![Word::Swap]),
+        shown_as: "Swap",
+    },
+    Rule {
+        name: "two-roll",
+        pattern: &[Is(Word::Number(2)), Is(Word::Roll)],
+        replacement: |_| Some(vec![Word::Rot]),
+        shown_as: "Rot",
+    },
+    Rule {
+        name: "plus-loop-const",
+        pattern: &[Pattern::Number, Is(Word::PlusLoop)],
+        replacement: |c| Some(vec![Word::PlusLoopConst(c[0].number())]),
+        shown_as: "PlusLoopConst(a)",
+    },
+    Rule {
+        name: "const-i-plus",
+        pattern: &[Pattern::Number, Is(Word::I), Is(Word::Plus)],
+        replacement: |c| Some(vec![Word::IPlusConst(c[0].number())]),
+        shown_as: "IPlusConst(a)",
+    },
+    Rule {
+        name: "dup-mod-const",
+        pattern: &[Is(Word::Dup), Pattern::Number, Is(Word::Mod)],
+        replacement: |c| Some(vec![Word::DupModConst(c[0].number())]),
+        shown_as: "DupModConst(a)",
+    },
+    Rule {
+        name: "i-one-plus",
+        pattern: &[Is(Word::I), Is(Word::OnePlus)],
+        replacement: |_| Some(vec![Word::IPlusConst(1)]),
+        shown_as: "IPlusConst(1)",
+    },
+    Rule {
+        name: "i-const-plus",
+        pattern: &[Is(Word::I), Pattern::Number, Is(Word::Plus)],
+        replacement: |c| Some(vec![Word::IPlusConst(c[0].number())]),
+        shown_as: "IPlusConst(a)",
+    },
+    // text printed back to back is printed with one write
+    Rule {
+        name: "join-quotes",
+        pattern: &[Pattern::Quote, Pattern::Quote],
+        replacement: |c| Some(vec![Word::Quote(c[0].text().to_string() + c[1].text())]),
+        shown_as: "Quote(a b)",
+    },
+    Rule {
+        name: "join-quote-cr",
+        pattern: &[Pattern::Quote, Is(Word::Cr)],
+        replacement: |c| Some(vec![Word::Quote(c[0].text().to_string() + "\n")]),
+        shown_as: "Quote(a \\n)",
+    },
+    Rule {
+        name: "dot-quote",
+        pattern: &[Is(Word::Dot), Pattern::Quote],
+        replacement: |c| Some(vec![Word::DotQuote(c[0].text().to_string())]),
+        shown_as: "DotQuote(a)",
+    },
+    Rule {
+        name: "dot-cr",
+        pattern: &[Is(Word::Dot), Is(Word::Cr)],
+        replacement: |_| Some(vec![Word::DotQuote("\n".to_string())]),
+        shown_as: "DotQuote(\\n)",
+    },
+    Rule {
+        name: "join-dot-quote",
+        pattern: &[Pattern::DotQuote, Pattern::Quote],
+        replacement: |c| Some(vec![Word::DotQuote(c[0].text().to_string() + c[1].text())]),
+        shown_as: "DotQuote(a b)",
+    },
+    Rule {
+        name: "join-dot-quote-cr",
+        pattern: &[Pattern::DotQuote, Is(Word::Cr)],
+        replacement: |c| Some(vec![Word::DotQuote(c[0].text().to_string() + "\n")]),
+        shown_as: "DotQuote(a \\n)",
+    },
+];
+
+// rewrites that need to see more than a fixed run of words, listed and turned off like the rules
+struct Pass {
+    name: &'static str,
+    // whether it changed anything
+    run: fn(&mut Vec<Word>) -> bool,
+    shown_as: &'static str,
+}
+
+static PASSES: &[Pass] = &[Pass {
+    name: "dead-branches",
+    run: remove_dead_branches,
+    shown_as: "Number(a) If ... Else ... Then -> the part a selects",
+}];
+
+impl Pass {
+    fn describe(&self) -> String {
+        format!("{}: {}", self.name, self.shown_as)
+    }
+}
+
+// drops the part of an if that a constant condition never runs, one if at a time
+fn remove_dead_branches(words: &mut Vec<Word>) -> bool {
+    for i in 0..words.len().saturating_sub(1) {
+        let taken = match (&words[i], &words[i + 1]) {
+            (Word::Number(n), Word::If(_)) => *n != 0,
+            (Word::Number(n), Word::NotIf(_)) => *n == 0,
+            _ => continue,
+        };
+
+        // the else and then belonging to this if, skipping over nested ones
+        let mut depth = 0;
+        let mut else_index = None;
+        let mut then_index = None;
+        for (j, word) in words.iter().enumerate().skip(i + 2) {
+            match word {
+                Word::If(_) | Word::NotIf(_) => depth += 1,
+                Word::Else(_) if depth == 0 => else_index = Some(j),
+                Word::Then if depth == 0 => {
+                    then_index = Some(j);
+                    break;
+                }
+                Word::Then => depth -= 1,
+                _ => {}
+            }
+        }
+        // an if left open is an error for resolve_control_flow to report
+        let Some(then_index) = then_index else {
+            continue;
+        };
+
+        record_rewrite(|| "dead-branches".to_string());
+        match (taken, else_index) {
+            (true, Some(else_index)) => {
+                words.drain(else_index..=then_index);
+                words.drain(i..i + 2);
+            }
+            (true, None) => {
+                words.remove(then_index);
+                words.drain(i..i + 2);
+            }
+            (false, Some(else_index)) => {
+                words.remove(then_index);
+                words.drain(i..=else_index);
+            }
+            (false, None) => {
+                words.drain(i..=then_index);
+            }
+        }
+        return true;
+    }
+    false
+}
+
+// how one interpreter optimizes the code it parses
+#[derive(Debug, Clone)]
+pub(crate) struct OptimizerSettings {
+    // off runs code exactly as written, neither rewritten by rules nor inlined
+    pub(crate) optimizing: bool,
+    // names of the rules turned off, for narrowing down a misbehaving optimization
+    disabled_rules: HashSet<&'static str>,
+}
+
+impl Default for OptimizerSettings {
+    fn default() -> OptimizerSettings {
+        OptimizerSettings {
+            optimizing: true,
+            disabled_rules: HashSet::new(),
+        }
+    }
+}
+
+impl OptimizerSettings {
+    // turns the rule or pass called name on or off for everything parsed from now on
+    pub(crate) fn set_rule_enabled(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        let name = find_rule(name)?;
+        if enabled {
+            self.disabled_rules.remove(name);
+        } else {
+            self.disabled_rules.insert(name);
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    // every rewrite made while recording, so a divergence can say which ones might be to blame
+    static REWRITES: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn start_recording() {
+    REWRITES.with(|rewrites| *rewrites.borrow_mut() = Some(Vec::new()));
+}
+
+// the rewrites made since start_recording, without duplicates
+pub(crate) fn stop_recording() -> Vec<String> {
+    let mut recorded = REWRITES.with(|rewrites| rewrites.borrow_mut().take().unwrap_or_default());
+    let mut seen = HashSet::new();
+    recorded.retain(|rewrite| seen.insert(rewrite.clone()));
+    recorded
+}
+
+// describe is only called while recording, since most code is never verified
+pub(crate) fn record_rewrite(describe: impl FnOnce() -> String) {
+    REWRITES.with(|rewrites| {
+        if let Some(rewrites) = rewrites.borrow_mut().as_mut() {
+            rewrites.push(describe());
+        }
+    });
+}
+
+// the name of the rule or pass called name, as a static string
+fn find_rule(name: &str) -> Result<&'static str, String> {
+    let rules = RULES.iter().map(|rule| rule.name);
+    let passes = PASSES.iter().map(|pass| pass.name);
+    rules
+        .chain(passes)
+        .find(|rule| *rule == name)
+        .ok_or_else(|| format!("Unknown optimization rule {name}"))
+}
+
+// every rule then every pass, one line each
+pub(crate) fn describe_rules() -> Vec<String> {
+    let rules = RULES.iter().map(Rule::describe);
+    rules.chain(PASSES.iter().map(Pass::describe)).collect()
+}
+
+impl Rule {
+    // the captures if the pattern matches words starting at the first one
+    fn matches(&self, words: &[Word]) -> Option<Vec<Capture>> {
+        if words.len() < self.pattern.len() {
+            return None;
+        }
+        let mut captures = Vec::new();
+        for (pattern, word) in self.pattern.iter().zip(words) {
+            match (pattern, word) {
+                (Is(expected), word) if expected == word => {}
+                (Pattern::Number, Word::Number(n)) => captures.push(Capture::Number(*n)),
+                (Pattern::Quote, Word::Quote(text)) | (Pattern::DotQuote, Word::DotQuote(text)) => {
+                    captures.push(Capture::Text(text.clone()))
+                }
+                (Pattern::If, Word::If(target)) => captures.push(Capture::Number(*target as i64)),
+                (Pattern::Quotation, Word::Quotation(body)) if !body.contains(&Word::Exit) => {
+                    captures.push(Capture::Code(body.clone()))
+                }
+                _ => return None,
+            }
+        }
+        Some(captures)
+    }
+
+    // how the rule reads for --list-rules
+    fn describe(&self) -> String {
+        let mut capture_names = 'a'..;
+        let pattern: Vec<String> = self
+            .pattern
+            .iter()
+            .map(|pattern| match pattern {
+                Is(word) => format!("{word:?}"),
+                Pattern::Number => format!("Number({})", capture_names.next().unwrap()),
+                Pattern::Quote => format!("Quote({})", capture_names.next().unwrap()),
+                Pattern::DotQuote => format!("DotQuote({})", capture_names.next().unwrap()),
+                Pattern::If => format!("If({})", capture_names.next().unwrap()),
+                Pattern::Quotation => format!("Quotation({})", capture_names.next().unwrap()),
+            })
+            .collect();
+        format!("{}: {} -> {}", self.name, pattern.join(" "), self.shown_as)
+    }
+}
+
+// rewrites words with the enabled rules until none of them match anywhere
+pub(crate) fn optimization_pass(words: &mut Vec<Word>, settings: &OptimizerSettings) {
+    if !settings.optimizing {
+        return;
+    }
+    let disabled = &settings.disabled_rules;
+    let rules: Vec<&Rule> = RULES
+        .iter()
+        .filter(|rule| !disabled.contains(rule.name))
+        .collect();
+    let passes: Vec<&Pass> = PASSES
+        .iter()
+        .filter(|pass| !disabled.contains(pass.name))
+        .collect();
+
+    // every rule and pass shrinks the code or removes a quotation, so this always finishes
+    let mut changed = true;
+    while changed {
+        changed = false;
+        let mut i = 0;
+        while i < words.len() {
+            let rewrite = rules.iter().find_map(|rule| {
+                let replacement = (rule.replacement)(&rule.matches(&words[i..])?)?;
+                record_rewrite(|| rule.name.to_string());
+                Some((rule.pattern.len(), replacement))
+            });
+            match rewrite {
+                // the replacement might start another match, so look at the same place again
+                Some((len, replacement)) => {
+                    words.splice(i..i + len, replacement);
+                    changed = true;
+                }
+                None => i += 1,
+            }
+        }
+
+        // folding can leave a constant condition, and removing a branch can leave more to fold
+        for pass in &passes {
+            while (pass.run)(words) {
+                changed = true;
+            }
+        }
+    }
+}
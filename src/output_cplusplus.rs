@@ -1,13 +1,28 @@
 use crate::parsing::Word;
+use crate::SUBSTITUTE_IOR;
 
 // this file is responsible for taking a vec of words, and outputting a c++ code string which runs the forth line
+
+// ends the program the way the interpreter ends a line
+const EXIT: &str = "std::cout << \" OK\\n\"; return 0;\n";
 pub(crate) fn output_cplusplus(input: &Vec<Word>) -> String {
     let mut output: String = Default::default();
 
     //we know we always have some things we need to do to set up
     //namely, create the data and control stacks
     const HEADER: &str = "
+#include <algorithm>
+#include <cctype>
+#include <chrono>
+#include <ctime>
+#include <cstdlib>
+#include <cstring>
 #include <iostream>
+#include <map>
+#include <string>
+#include <thread>
+#include <unistd.h>
+#include <utility>
 #include <vector>
 #define int64 int64_t
 
@@ -17,7 +32,43 @@ int64 pop(std::vector<int64> *stack) {
     return x;
 }
 
+// what replaces has set up for substitute, keyed by lower case name
+std::map<std::string, std::string> substitutions;
+
+std::string lower(std::string text) {
+    std::transform(text.begin(), text.end(), text.begin(), [](unsigned char c) { return std::tolower(c); });
+    return text;
+}
+
+// the text with each known %name% replaced, and how many were
+std::pair<std::string, int64> substitute(const std::string &source) {
+    std::string result;
+    int64 count = 0;
+    size_t i = 0;
+    while (i < source.size()) {
+        const size_t end = source[i] == '%' ? source.find('%', i + 1) : std::string::npos;
+        if (end == i + 1) {
+            result += '%';
+            i += 2;
+        } else if (end != std::string::npos) {
+            const auto found = substitutions.find(lower(source.substr(i + 1, end - i - 1)));
+            if (found != substitutions.end()) {
+                result += found->second;
+                count++;
+            } else {
+                result += source.substr(i, end - i + 1);
+            }
+            i = end + 1;
+        } else {
+            result += source[i];
+            i++;
+        }
+    }
+    return {result, count};
+}
+
 int main() {
+const auto program_start = std::chrono::steady_clock::now();
 std::vector<int64> stack = {};
 
 ";
@@ -30,8 +81,8 @@ std::vector<int64> stack = {};
     // this will add the instructions for the state machine to run
     output.push_str(instruction_tape(&input).as_str());
 
-    const FOOTER: &str = "std::cout << \" OK\\n\";}";
-    output.push_str(FOOTER);
+    output.push_str(EXIT);
+    output.push('}');
 
     output
 }
@@ -39,7 +90,9 @@ std::vector<int64> stack = {};
 // the actual switch statement to print the needed c++ code
 fn instruction_tape(words: &&Vec<Word>) -> String {
     let mut output: String = "".to_string();
-    for word in *words {
+    // number of ofs in each open case, so endcase knows how many blocks to close
+    let mut case_of_counts: Vec<usize> = Vec::new();
+    for (i, word) in words.iter().enumerate() {
         match word {
             Word::Dup => {
                 output.push_str("stack.push_back(stack.back());\n")
@@ -59,6 +112,16 @@ fn instruction_tape(words: &&Vec<Word>) -> String {
             Word::Do => {
                 output.push_str("int64 index = pop(&stack); int64 limit = pop(&stack); for(int64 i = index; i < limit; i++){\n")
             }
+            Word::Leave(_) => {
+                output.push_str("break;\n")
+            }
+            Word::Unloop => {
+                // the for loop's variables go away with it
+            }
+            Word::Exit => {
+                // the whole program is the one word, so leaving it is the end
+                output.push_str(EXIT);
+            }
             Word::If(_) => {
                 output.push_str("if (pop(&stack) != 0) {\n");
             }
@@ -68,6 +131,26 @@ fn instruction_tape(words: &&Vec<Word>) -> String {
             Word::Then => {
                 output.push_str("}\n")
             }
+            Word::Case => {
+                case_of_counts.push(0);
+                output.push_str("{\n")
+            }
+            Word::Of(_) => {
+                if let Some(count) = case_of_counts.last_mut() {
+                    *count += 1;
+                }
+                output.push_str("{const int64 of_value = pop(&stack); if (stack.back() == of_value) { stack.pop_back();\n")
+            }
+            Word::EndOf(_) => {
+                output.push_str("} else {\n")
+            }
+            Word::EndCase => {
+                // close the else and the of_value scope of every of, then the case itself
+                let count = case_of_counts.pop().unwrap_or(0);
+                output.push_str("stack.pop_back();\n");
+                output.push_str(&"}}".repeat(count));
+                output.push_str("}\n")
+            }
             Word::Plus => {
                 output.push_str("{const int64 size = stack.size(); stack[size-2] += pop(&stack);}\n")
             }
@@ -78,19 +161,19 @@ fn instruction_tape(words: &&Vec<Word>) -> String {
                 output.push_str("if (pop(&stack) == pop(&stack)) { stack.push_back(1); } else { stack.push_back(0); }\n")
             }
             Word::Greater => {
-                todo!()
+                output.push_str("{int64 two = pop(&stack); int64 one = pop(&stack); stack.push_back(one > two ? 1 : 0);}\n")
             }
             Word::Less => {
-                todo!()
+                output.push_str("{int64 two = pop(&stack); int64 one = pop(&stack); stack.push_back(one < two ? 1 : 0);}\n")
             }
             Word::MultDivide => {
-                todo!()
+                output.push_str("{int64 three = pop(&stack); int64 two = pop(&stack); int64 one = pop(&stack); stack.push_back(one * two / three);}\n")
             }
             Word::Mod => {
                 output.push_str("{int64 two = pop(&stack); int64 one = pop(&stack); stack.push_back( one % two);}\n")
             }
             Word::Mult => {
-                todo!()
+                output.push_str("{const int64 size = stack.size(); stack[size-2] *= pop(&stack);}\n")
             }
             Word::Drop => {
                 output.push_str("stack.pop_back();\n")
@@ -102,9 +185,146 @@ fn instruction_tape(words: &&Vec<Word>) -> String {
             Word::I => {
                 output.push_str("stack.push_back(i);\n")
             }
+            Word::Over => {
+                output.push_str("stack.push_back(stack[stack.size()-2]);\n")
+            }
+            Word::Nip => {
+                output.push_str("stack.erase(stack.end()-2);\n")
+            }
+            Word::Tuck => {
+                output.push_str("stack.insert(stack.end()-2, stack.back());\n")
+            }
+            Word::Pick => {
+                output.push_str("{const int64 depth = pop(&stack); stack.push_back(stack[stack.size()-1-depth]);}\n")
+            }
+            Word::Roll => {
+                output.push_str("{const int64 depth = pop(&stack); const int64 x = stack[stack.size()-1-depth]; stack.erase(stack.end()-1-depth); stack.push_back(x);}\n")
+            }
+            Word::QuestionDup => {
+                output.push_str("if (stack.back() != 0) { stack.push_back(stack.back()); }\n")
+            }
+            Word::Depth => {
+                output.push_str("stack.push_back(stack.size());\n")
+            }
+            Word::TwoDup => {
+                output.push_str("{const int64 size = stack.size(); stack.push_back(stack[size-2]); stack.push_back(stack[size-1]);}\n")
+            }
+            Word::TwoDrop => {
+                output.push_str("stack.resize(stack.size()-2);\n")
+            }
+            Word::TwoSwap => {
+                output.push_str("std::rotate(stack.end()-4, stack.end()-2, stack.end());\n")
+            }
+            Word::TwoOver => {
+                output.push_str("{const int64 size = stack.size(); stack.push_back(stack[size-4]); stack.push_back(stack[size-3]);}\n")
+            }
+            Word::TwoRot => {
+                output.push_str("std::rotate(stack.end()-6, stack.end()-4, stack.end());\n")
+            }
             Word::Quote(w) => {
                 output.push_str(&("std::cout << \"".to_owned() + &*w.replace('\n', "\\n") + "\";\n"))
             }
+            Word::StringLiteral(w) | Word::PlacedString(_, w) => {
+                // an array of its own, since identical c++ literals can share storage
+                output.push_str(&("{static char literal[] = \"".to_owned() + &*w.replace('\n', "\\n") + "\"; stack.push_back((int64)literal); stack.push_back(" + w.len().to_string().as_str() + ");}\n"))
+            }
+            Word::Locals(args, uninitialized) => {
+                // each frame is its own c++ block, so inlined frames shadow the outer ones like they do at runtime
+                output.push_str("{\n");
+                for index in (0..*args).rev() {
+                    output.push_str(&format!("int64 local_{index} = pop(&stack);\n"));
+                }
+                for index in *args..(args + uninitialized) {
+                    output.push_str(&format!("int64 local_{index} = 0;\n"));
+                }
+            }
+            Word::LocalFetch(index) => {
+                output.push_str(&format!("stack.push_back(local_{index});\n"))
+            }
+            Word::LocalStore(index) => {
+                output.push_str(&format!("local_{index} = pop(&stack);\n"))
+            }
+            Word::EndLocals => {
+                // an exit returns from inside the frame, so its block has to stay open for the
+                // code after the exit
+                if words.get(i + 1) != Some(&Word::Exit) {
+                    output.push_str("}\n")
+                }
+            }
+            Word::Emit => {
+                output.push_str("std::cout << (char)pop(&stack);\n")
+            }
+            Word::Key => {
+                output.push_str("std::cout.flush(); stack.push_back(getchar());\n")
+            }
+            Word::Accept => {
+                output.push_str("{const int64 max = pop(&stack); char *buf = (char *)pop(&stack); std::string line; std::getline(std::cin, line); const int64 len = std::min((int64)line.size(), max); memcpy(buf, line.data(), len); stack.push_back(len);}\n")
+            }
+            Word::AtXy => {
+                output.push_str("{const int64 row = pop(&stack); const int64 column = pop(&stack); if (isatty(1)) { std::cout << \"\\x1b[\" << row + 1 << \";\" << column + 1 << \"H\"; }}\n")
+            }
+            Word::Page => {
+                output.push_str("if (isatty(1)) { std::cout << \"\\x1b[2J\\x1b[H\"; } else { std::cout << \"\\n\"; }\n")
+            }
+            Word::Ms => {
+                output.push_str("std::cout.flush(); fflush(stdout); std::this_thread::sleep_for(std::chrono::milliseconds(pop(&stack)));\n")
+            }
+            Word::TimeAndDate => {
+                output.push_str("{const std::time_t now = std::time(nullptr); const std::tm *t = std::gmtime(&now); stack.push_back(t->tm_sec); stack.push_back(t->tm_min); stack.push_back(t->tm_hour); stack.push_back(t->tm_mday); stack.push_back(t->tm_mon + 1); stack.push_back(t->tm_year + 1900);}\n")
+            }
+            Word::Utime => {
+                output.push_str("stack.push_back(std::chrono::duration_cast<std::chrono::microseconds>(std::chrono::system_clock::now().time_since_epoch()).count());\n")
+            }
+            Word::UtimeNs => {
+                output.push_str("stack.push_back(std::chrono::duration_cast<std::chrono::nanoseconds>(std::chrono::system_clock::now().time_since_epoch()).count());\n")
+            }
+            Word::Ticks => {
+                output.push_str("stack.push_back(std::chrono::duration_cast<std::chrono::milliseconds>(std::chrono::steady_clock::now() - program_start).count());\n")
+            }
+            Word::Type => {
+                output.push_str("{int64 len = pop(&stack); fwrite((const char *)pop(&stack), 1, len, stdout);}\n")
+            }
+            Word::Compare => {
+                output.push_str("{const int64 len2 = pop(&stack); const char *two = (const char *)pop(&stack); const int64 len1 = pop(&stack); const char *one = (const char *)pop(&stack); int res = memcmp(one, two, std::min(len1, len2)); if (res == 0) { res = (len1 > len2) - (len1 < len2); } stack.push_back((res > 0) - (res < 0));}\n")
+            }
+            Word::Search => {
+                output.push_str("{const int64 len2 = pop(&stack); const char *two = (const char *)pop(&stack); const int64 len1 = stack.back(); const char *one = (const char *)stack[stack.size()-2]; const char *found = std::search(one, one + len1, two, two + len2); if (found == one + len1 && len2 != 0) { stack.push_back(0); } else { stack[stack.size()-2] = (int64)found; stack.back() = len1 - (found - one); stack.push_back(1); }}\n")
+            }
+            Word::SlashString => {
+                output.push_str("{const int64 n = pop(&stack); stack[stack.size()-2] += n; stack.back() -= n;}\n")
+            }
+            Word::MinusTrailing => {
+                output.push_str("{const char *str = (const char *)stack[stack.size()-2]; while (stack.back() > 0 && str[stack.back()-1] == ' ') { stack.back() -= 1; }}\n")
+            }
+            Word::Blank => {
+                output.push_str("{const int64 len = pop(&stack); memset((char *)pop(&stack), ' ', len);}\n")
+            }
+            Word::Fill => {
+                output.push_str("{const int64 byte = pop(&stack); const int64 len = pop(&stack); memset((char *)pop(&stack), byte, len);}\n")
+            }
+            Word::Erase => {
+                output.push_str("{const int64 len = pop(&stack); memset((char *)pop(&stack), 0, len);}\n")
+            }
+            Word::Move => {
+                output.push_str("{const int64 len = pop(&stack); char *to = (char *)pop(&stack); memmove(to, (const char *)pop(&stack), len);}\n")
+            }
+            Word::CMove => {
+                output.push_str("{const int64 len = pop(&stack); char *to = (char *)pop(&stack); const char *from = (const char *)pop(&stack); for (int64 n = 0; n < len; n++) { to[n] = from[n]; }}\n")
+            }
+            Word::CMoveUp => {
+                output.push_str("{const int64 len = pop(&stack); char *to = (char *)pop(&stack); const char *from = (const char *)pop(&stack); for (int64 n = len - 1; n >= 0; n--) { to[n] = from[n]; }}\n")
+            }
+            // folded into the string literal before it when parsed
+            Word::SLiteral => {}
+            Word::Replaces => {
+                output.push_str("{const int64 name_len = pop(&stack); const char *name = (const char *)pop(&stack); const int64 len = pop(&stack); const char *text = (const char *)pop(&stack); substitutions[lower(std::string(name, std::max(name_len, (int64)0)))] = std::string(text, std::max(len, (int64)0));}\n")
+            }
+            Word::Substitute => {
+                output.push_str(&format!("{{const int64 buffer_len = pop(&stack); char *buffer = (char *)pop(&stack); const int64 len = pop(&stack); const char *text = (const char *)pop(&stack); const auto substituted = substitute(std::string(text, std::max(len, (int64)0))); const int64 written = std::min((int64)substituted.first.size(), std::max(buffer_len, (int64)0)); memcpy(buffer, substituted.first.data(), written); stack.push_back((int64)buffer); stack.push_back(written); stack.push_back(written < (int64)substituted.first.size() ? {SUBSTITUTE_IOR} : substituted.second);}}\n"))
+            }
+            Word::Cells => {
+                output.push_str("stack.back() *= 8;\n")
+            }
             Word::OnePlus => {
                 output.push_str("{const int64 size = stack.size(); stack[size-1] += 1;}\n")
             }
@@ -151,11 +371,51 @@ fn instruction_tape(words: &&Vec<Word>) -> String {
             //unsupported for now
             Word::Function(_) => {}
             Word::EndFunction => {}
-            Word::Word(_) => {}
+            Word::Word(_) | Word::Call(_) => {}
             Word::Reset => {}
             Word::Exclamation => {}
             Word::At => {}
             Word::UDotR => {}
+            Word::Value(_) => {}
+            Word::TwoVariable(_) => {}
+            Word::TwoConstant(_) => {}
+            Word::TwoValue(_) => {}
+            Word::Buffer(_) => {}
+            Word::Create(_) => {}
+            Word::Tick(_) => {}
+            Word::Quotation(_) => {}
+            Word::Execute => {}
+            Word::KeyQuestion => {}
+            Word::Ekey => {}
+            Word::BeginStructure(_) => {}
+            Word::PlusField(_) => {}
+            Word::FieldColon(_) => {}
+            Word::CFieldColon(_) => {}
+            Word::FFieldColon(_) => {}
+            Word::EndStructure => {}
+            Word::Defined(_) => {}
+            Word::Undefined(_) => {}
+            Word::Comma => {}
+            Word::Allot => {}
+            Word::Here => {}
+            Word::Allocate => {}
+            Word::Free => {}
+            Word::Resize => {}
+            Word::Block => {}
+            Word::BlockBuffer => {}
+            Word::Update => {}
+            Word::SaveBuffers => {}
+            Word::Flush => {}
+            Word::Load => {}
+            Word::Thru => {}
+            Word::List => {}
+            Word::Scr => {}
+            Word::To(_) => {}
+            Word::PlusTo(_) => {}
+            Word::TwoAt => {}
+            Word::TwoExclamation => {}
+            Word::Evaluate => {}
+            Word::SourceId => {}
         }
     }
 
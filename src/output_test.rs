@@ -1,28 +1,27 @@
 #[cfg(test)]
 mod tests {
-    use crate::parsing::parse_line;
-    use crate::{parsing, run_line, Error, State};
-    use ahash::{HashMap, RandomState};
+    use crate::blocks::BlockStore;
+    use crate::clock::Clock;
+    use crate::dictionary::EntryKind;
+    use crate::parsing::{parse_line, Word};
+    use crate::terminal::{KeySource, Terminal};
+    use crate::{interpret_line, parsing, prelude, run_line, stack_effect, Error, Forth, State};
+    use std::cell::RefCell;
     use std::io::Write;
+    use std::rc::Rc;
 
     fn get_output_from_line(l: String) -> Result<String, Error> {
         let mut stack = Vec::with_capacity(10);
-        let mut state: State;
-        state = State {
-            defined_words: HashMap::with_capacity_and_hasher(10, RandomState::new()),
-            variables: HashMap::with_capacity_and_hasher(10, RandomState::new()),
-            if_control_stack: Vec::with_capacity(10),
-            loop_control_stack: Vec::with_capacity(10),
-            internal_buffer: Vec::with_capacity(10),
-        };
+        let mut state = State::new();
 
         let mut buffer: Vec<u8> = Vec::new();
-        let parsed_line = parse_line(parsing::normalize_line(l).clone()).unwrap();
+        let mut parsed_line = parse_line(parsing::normalize_line(l).clone(), &state).unwrap();
+        parsing::place_strings(&mut parsed_line, &mut state.memory);
 
         let line_result = run_line(
             &mut stack,
             &mut state,
-            &parsed_line,
+            Rc::new(parsed_line),
             &mut buffer as &mut dyn Write,
         );
         if let Err(e) = line_result {
@@ -32,6 +31,26 @@ mod tests {
         }
     }
 
+    // runs several lines through the same pipeline as main's line loop
+    fn get_output_from_lines(lines: &[&str]) -> Result<String, Error> {
+        get_output_from_lines_with(State::new(), lines)
+    }
+
+    fn get_output_from_lines_with(mut state: State, lines: &[&str]) -> Result<String, Error> {
+        let mut stack = Vec::with_capacity(10);
+
+        let mut buffer: Vec<u8> = Vec::new();
+        for l in lines {
+            interpret_line(
+                &mut stack,
+                &mut state,
+                l.to_string(),
+                &mut buffer as &mut dyn Write,
+            )?;
+        }
+        Ok(String::from_utf8(buffer)?)
+    }
+
     #[test]
     fn basic_line() {
         let result = get_output_from_line(".\" hello world \"".to_string());
@@ -81,4 +100,1096 @@ mod tests {
         let result = get_output_from_line(input.to_string());
         assert_eq!(result.unwrap(), output.to_string());
     }
+
+    #[test]
+    fn type_string_literal() {
+        let result = get_output_from_line("S\" Hello World \" TYPE".to_string());
+        assert_eq!(result.unwrap(), "Hello World".to_string());
+
+        // each literal has its own storage, which stays put however often it runs
+        let lines = [
+            ": a s\" abc \" ; : b s\" abc \" ;",
+            "a 88 fill b type a type",
+            "a drop a drop = .",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "abcXXX1".to_string())
+    }
+
+    #[test]
+    fn evaluate_string() {
+        let input = "S\" : sq DUP * ; 7 sq . \" EVALUATE 3 sq .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "499".to_string())
+    }
+
+    #[test]
+    fn evaluate_nested_source_id() {
+        let input = "SOURCE-ID . S\" SOURCE-ID . \" EVALUATE SOURCE-ID .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "0-10".to_string())
+    }
+
+    #[test]
+    fn evaluate_recursion_overflows() {
+        let input = ": again S\" again \" EVALUATE ; again";
+        let result = get_output_from_line(input.to_string());
+        assert!(result.is_err())
+    }
+
+    #[test]
+    fn locals() {
+        let input = ": hyp {: a b | c -- d :} a a * b b * + TO c c ; 3 4 hyp .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "25".to_string())
+    }
+
+    #[test]
+    fn nested_locals() {
+        let input = ": inc {: x :} x 1+ ; : twice {: x :} x inc x + x ; 5 twice . .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "511".to_string())
+    }
+
+    #[test]
+    fn case_statement() {
+        let input = ": name CASE 1 OF .\" one \" ENDOF 2 OF .\" two \" ENDOF .\" other \" ENDCASE ; 1 name 2 name 3 name";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "onetwoother".to_string())
+    }
+
+    #[test]
+    fn nested_case_in_if() {
+        let input = ": f DUP 0 > IF CASE 1 OF 10 ENDOF 2 OF 1 IF 20 ELSE 0 THEN ENDOF 0 SWAP ENDCASE ELSE DROP -1 THEN . ; 2 f 1 f 5 f 0 f";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "20100-1".to_string())
+    }
+
+    #[test]
+    fn inlined_word_inside_if() {
+        let input = ": two 1 1 + ; : f IF two two + ELSE 7 THEN . ; 1 f 0 f 1 f";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "474".to_string())
+    }
+
+    #[test]
+    fn stack_words() {
+        let input =
+            "1 2 OVER . . . 1 2 NIP . 1 2 TUCK . . . 1 2 3 2 PICK . . . . 1 2 3 2 ROLL . . .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "12122121321132".to_string())
+    }
+
+    #[test]
+    fn double_stack_words() {
+        let input = "1 2 2DUP . . . . 1 2 3 2DROP . 1 2 3 4 2SWAP . . . . 1 2 3 4 2OVER . . . . . . 1 2 3 4 5 6 2ROT . . . . . .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "212112143214321216543".to_string())
+    }
+
+    #[test]
+    fn conditional_dup_and_depth() {
+        let input = "0 ?DUP DEPTH . . 5 ?DUP DEPTH . . . 1 2 3 -ROT . . .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "10255213".to_string())
+    }
+
+    #[test]
+    fn redefine_builtin_name() {
+        // the builtin over would leave 1 2 1
+        let input = ": over SWAP ; 1 2 OVER . . DEPTH .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "120".to_string())
+    }
+
+    #[test]
+    fn variables() {
+        let input = "123 VARIABLE v v @ . 5 v ! v @ . . 16 BUFFER: buf 42 buf 1 CELLS + ! buf 1 CELLS + @ .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "0512342".to_string())
+    }
+
+    #[test]
+    fn values() {
+        let input = "5 VALUE v v . 7 TO v v . 3 +TO v v . 1 2 2VALUE q 3 4 TO q q . .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "571043".to_string())
+    }
+
+    #[test]
+    fn double_cell_words() {
+        let input = "2VARIABLE d 1 2 d 2! d 2@ . . 3 4 2CONSTANT p p . .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "2143".to_string())
+    }
+
+    #[test]
+    fn constant_folding_follows_redefinition() {
+        let input = "10 CONSTANT ten : f ten 1+ ; f . 20 CONSTANT ten f .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "1121".to_string())
+    }
+
+    #[test]
+    fn newest_definition_wins() {
+        let input = ": x 1 ; 2 CONSTANT x x . : x 3 ; x .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "23".to_string())
+    }
+
+    #[test]
+    fn builtins_can_be_shadowed() {
+        // seen from the end of the definition on, by later words only
+        let input = ": sq dup * ; : dup dup 42 ; 1 dup . . . 3 sq .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "42119".to_string());
+
+        let lines = [": over 7 ;", "5 constant abs", "over . abs ."];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "75".to_string())
+    }
+
+    #[test]
+    fn redefinition_breaks_inlining() {
+        let input = ": a 1 ; : b a ; b . : a 2 ; b .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "12".to_string())
+    }
+
+    #[test]
+    fn redefinition_breaks_inlining_transitively() {
+        let input = ": a 1 ; : b a ; : c b b + ; c . : a 2 ; c .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "24".to_string())
+    }
+
+    #[test]
+    fn large_words_are_not_inlined() {
+        let mut stack = Vec::with_capacity(10);
+        let mut state = State::new();
+        let mut buffer: Vec<u8> = Vec::new();
+        let big = vec!["dup drop"; 40].join(" ");
+        let line = format!(": small 1 + ; : big {big} ; : f small big ;");
+        interpret_line(&mut stack, &mut state, line, &mut buffer).unwrap();
+
+        let words = parse_line("small big".to_string(), &state).unwrap();
+        let (inlined, depends) = parsing::inline_function(&"f".to_string(), &words, &state);
+        assert_eq!(
+            inlined,
+            vec![Word::Number(1), Word::Plus, Word::Word("big".to_string())]
+        );
+        assert!(depends.contains("small") && !depends.contains("big"));
+
+        // but fully inlining, as for c++, still inlines everything
+        let (inlined, _) = parsing::inline_fully(&"f".to_string(), &words, &state);
+        assert_eq!(inlined.len(), 82);
+    }
+
+    #[test]
+    fn calls_are_resolved_by_symbol() {
+        let mut state = State::new();
+        let mut stack = Vec::new();
+        let mut buffer: Vec<u8> = Vec::new();
+        let lines = [
+            ": a b ;",
+            ": b 1 . ;",
+            "a",
+            ": b 2 . ;",
+            "a",
+            ": down dup 0 > if dup . -1 + down then ;",
+            "3 down drop",
+        ];
+        for l in lines {
+            interpret_line(&mut stack, &mut state, l.to_string(), &mut buffer).unwrap();
+        }
+        assert_eq!(String::from_utf8(buffer).unwrap(), "12321");
+
+        // definitions hold symbols, not names to look up
+        let down = state.dictionary.colon("down").unwrap();
+        let symbol = down.words.iter().find_map(|w| match w {
+            Word::Call(symbol) => Some(*symbol),
+            _ => None,
+        });
+        assert_eq!(state.dictionary.symbol_name(symbol.unwrap()), "down");
+        assert!(!down.words.iter().any(|w| matches!(w, Word::Word(_))));
+    }
+
+    #[test]
+    fn create_comma() {
+        let input = "CREATE tbl 10 , 20 , tbl 1 CELLS + @ . HERE tbl 2 CELLS + = .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "201".to_string())
+    }
+
+    #[test]
+    fn reset_keeps_colon_definitions() {
+        let input = "5 CONSTANT k : f 7 ; RESET f .";
+        let result = get_output_from_line(input.to_string());
+        assert_eq!(result.unwrap(), "7".to_string());
+
+        let result = get_output_from_line("5 CONSTANT k RESET k".to_string());
+        assert!(result.is_err());
+
+        // nor through a word it was inlined into
+        let result = get_output_from_line("5 CONSTANT k : f k ; f . RESET f .".to_string());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Unrecognized word Word(\"k\") in f (input:0)"
+        )
+    }
+
+    #[test]
+    fn bracket_if_else() {
+        let input =
+            "1 [IF] .\" yes \" [ELSE] .\" no \" [THEN] 0 [IF] .\" yes \" [ELSE] .\" no \" [THEN]";
+        let result = get_output_from_lines(&[input]);
+        assert_eq!(result.unwrap(), "yesno".to_string())
+    }
+
+    #[test]
+    fn bracket_if_nested_across_lines() {
+        let lines = [
+            "0 [IF]",
+            "1 [IF] .\" a \" [THEN] $zz",
+            "[ELSE] .\" b \"",
+            "1 [IF] .\" c \" [ELSE] .\" d \" [THEN]",
+            "[THEN] .\" e \"",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "bce".to_string())
+    }
+
+    #[test]
+    fn bracket_defined() {
+        let lines = [
+            ": sq DUP * ;",
+            "[DEFINED] sq [IF] 3 sq . [THEN] [UNDEFINED] cube [IF] .\" no cube \" [THEN]",
+            "[DEFINED] dup . [DEFINED] cube .",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "9no cube10".to_string())
+    }
+
+    #[test]
+    fn bracket_if_inside_definitions() {
+        let lines = [
+            ": sq dup * ;",
+            ": f [defined] sq [if] sq [else] dup + [then] 1 + ; 3 f .",
+            ": g [undefined] sq [if] sq [else] dup + [then] 1 + ; 3 g .",
+            // the flag comes from before the definition, not from the 1 compiled into it
+            "0 : h 1 [if] 2 [then] 3 ; h . .",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "10731".to_string());
+
+        let result = get_output_from_lines(&["1 [then] 2"]);
+        assert_eq!(result.unwrap_err().to_string(), "[then] without [if]");
+        let result = get_output_from_lines(&["[else]"]);
+        assert_eq!(result.unwrap_err().to_string(), "[else] without [if]");
+    }
+
+    #[test]
+    fn allocate_and_free() {
+        let lines = [
+            "variable p 2 cells allocate . p !",
+            "5 p @ ! 7 p @ 1 cells + ! p @ 2@ . .",
+            "p @ 3 cells resize . p ! p @ 2@ . . p @ 2 cells + @ .",
+            "p @ free . p @ free 0 = .",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "057057000".to_string())
+    }
+
+    #[test]
+    fn move_fill_erase() {
+        let lines = [
+            "variable p 16 allocate drop p !",
+            "p @ 16 120 fill",
+            "s\" abc \" p @ 1 + swap move p @ 5 type",
+            "p @ 16 erase p @ @ . p @ 1 cells + @ .",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "xabcx00".to_string())
+    }
+
+    #[test]
+    fn debug_memory_catches_misuse() {
+        let mut state = State::new();
+        state.memory.set_debug(true);
+        let result = get_output_from_lines_with(state, &["8 allocate drop dup free drop @"]);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("Use after free"));
+
+        let mut state = State::new();
+        state.memory.set_debug(true);
+        let result = get_output_from_lines_with(state, &["8 allocate drop dup free drop free"]);
+        assert!(result.unwrap_err().to_string().starts_with("Double free"));
+
+        let result = get_output_from_lines(&["8 allocate drop dup free drop @"]);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid memory access"));
+    }
+
+    #[test]
+    fn string_words() {
+        let lines = [
+            "s\" hello world \" s\" wor \" search . type s\" hello \" s\" xyz \" search . type",
+            "s\" abc \" s\" abd \" compare . s\" abc \" s\" ab \" compare . s\" abc \" 2dup compare .",
+            "s\" abcdef \" 2 /string 0 /string type depth .",
+            "create buf 8 allot buf 8 blank buf 3 120 fill buf 8 -trailing . drop",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "1world0hello-110cdef03".to_string())
+    }
+
+    #[test]
+    fn overlapping_cmove() {
+        let lines = [
+            "s\" xyz \" sliteral over dup 1 + 2 cmove> type",
+            "s\" xyz \" sliteral over dup 1 + 2 cmove type",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "xxyxxx".to_string());
+
+        // sliteral compiles its string once, so every run sees the same copy
+        let lines = [
+            ": s s\" abc \" sliteral ;",
+            "s drop 1 88 fill s type s drop s drop = .",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "Xbc1".to_string());
+        assert!(parse_line("s\" abc \" 1 /string sliteral".to_string(), &State::new()).is_err());
+    }
+
+    #[test]
+    fn substitute_string() {
+        let lines = [
+            "s\" Bob \" s\" name \" replaces create buf 40 allot",
+            "s\" hi %NAME%, 100%% %other% \" buf 40 substitute . type",
+            "s\" hi %name% \" buf 4 substitute . type",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "1hi Bob, 100% %other%-78hi B".to_string())
+    }
+
+    // a state using its own block file, so tests can run in parallel
+    fn state_with_block_file(name: &str) -> (State, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("{name}-{}.blk", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut state = State::new();
+        state.blocks = BlockStore::new(path.clone());
+        (state, path)
+    }
+
+    #[test]
+    fn load_blocks() {
+        let (state, path) = state_with_block_file("load_blocks");
+        let lines = [
+            "s\" 2 3 + . \" 1 block swap move update",
+            "s\" : sq dup * ; \" 2 block swap move update",
+            "s\" 7 sq . \" 2 block 64 + swap move update",
+            "1 load 2 load 1 2 thru flush",
+        ];
+        let result = get_output_from_lines_with(state, &lines);
+        assert_eq!(result.unwrap(), "549549".to_string());
+
+        // a fresh state only sees what was written to the file
+        let mut state = State::new();
+        state.blocks = BlockStore::new(path.clone());
+        let result = get_output_from_lines_with(
+            state,
+            &["2 list scr @ . 3 block 64 -trailing . drop 2 load"],
+        );
+        let mut expected = "Screen 2\n 0 : sq dup * ;\n 1 7 sq .\n".to_string();
+        for line_number in 2..16 {
+            expected.push_str(&format!("{line_number:2} \n"));
+        }
+        expected.push_str("2049");
+        assert_eq!(result.unwrap(), expected);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn block_buffers_are_written_back() {
+        let (state, path) = state_with_block_file("block_buffers_are_written_back");
+        // more blocks than there are buffers, so some have to be written back to make room
+        let lines = [
+            "s\" 1 . \" 1 buffer swap move update s\" 2 . \" 2 buffer swap move update",
+            "s\" 3 . \" 3 buffer swap move update s\" 4 . \" 4 buffer swap move update",
+            "s\" 5 . \" 5 buffer swap move update save-buffers",
+        ];
+        get_output_from_lines_with(state, &lines).unwrap();
+
+        let mut state = State::new();
+        state.blocks = BlockStore::new(path.clone());
+        let result = get_output_from_lines_with(state, &["1 5 thru"]);
+        assert_eq!(result.unwrap(), "12345".to_string());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn block_numbers_are_checked() {
+        let (state, path) = state_with_block_file("block_numbers_are_checked");
+        get_output_from_lines_with(state, &["s\" 1 . \" 3 buffer swap move update flush"]).unwrap();
+        // the gap before block 3 is padded with blank blocks
+        let contents = std::fs::read(&path).unwrap();
+        assert_eq!(contents.len(), 3 * 1024);
+        assert!(contents[..2048].iter().all(|b| *b == b' '));
+
+        for line in [
+            "0 block",
+            "-1 block",
+            "9223372036854775807 block",
+            "1000000000000 buffer drop update flush",
+        ] {
+            let mut state = State::new();
+            state.blocks = BlockStore::new(path.clone());
+            let err = get_output_from_lines_with(state, &[line]).unwrap_err();
+            assert!(
+                err.to_string().starts_with("Invalid block number"),
+                "{line}"
+            );
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn structures() {
+        let lines = [
+            "begin-structure point field: p.x field: p.y cfield: p.tag end-structure point .",
+            "create pt point allot 5 pt p.x ! 7 pt p.y ! pt p.x @ . pt p.y @ .",
+            ": sum pt p.x @ pt p.y @ + ; sum . sum .",
+            "begin-structure s 1 +field a 3 +field b field: c ffield: d end-structure",
+            "s . 0 a . 0 b . 0 c . 0 d .",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "175712122401816".to_string())
+    }
+
+    #[test]
+    fn structure_fields_fold_into_addresses() {
+        let mut stack = Vec::with_capacity(10);
+        let mut state = State::new();
+        let mut buffer: Vec<u8> = Vec::new();
+        let line =
+            "begin-structure pair field: first field: second end-structure create p pair allot";
+        interpret_line(&mut stack, &mut state, line.to_string(), &mut buffer).unwrap();
+
+        let Some(EntryKind::Created(addr)) = state.dictionary.find("p").map(|e| &e.kind) else {
+            panic!("p should be created");
+        };
+        let words = parse_line("p second @ p first @".to_string(), &state).unwrap();
+        let (inlined, _) = parsing::inline_function(&"f".to_string(), &words, &state);
+        assert_eq!(
+            inlined,
+            vec![
+                Word::Number(addr + 8),
+                Word::At,
+                Word::Number(*addr),
+                Word::At
+            ]
+        )
+    }
+
+    #[test]
+    fn quotations() {
+        let lines = [
+            "create arr 1 , 2 , 3 ,",
+            ": each {: addr n xt :} n 0 do addr i cells + @ xt execute loop ;",
+            ": show arr 3 [: 10 * . ;] each ; show show",
+            ": nested [: [: 5 ;] ;] execute execute . ; nested",
+            "4 ' dup execute * . [: {: a :} a a + ;] 3 swap execute .",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "1020301020305166".to_string())
+    }
+
+    #[test]
+    fn executed_defining_words_need_a_name() {
+        for line in [
+            "5 ' value execute",
+            "1 2 ' 2constant execute",
+            "' create execute",
+            "8 ' buffer: execute",
+            "0 ' +to execute",
+            "0 4 ' field: execute",
+        ] {
+            let mut stack = vec![];
+            let mut state = State::new();
+            let mut buffer: Vec<u8> = Vec::new();
+            let err =
+                interpret_line(&mut stack, &mut state, line.to_string(), &mut buffer).unwrap_err();
+            assert!(err.to_string().starts_with("Expected a name"), "{line}");
+            assert!(state.dictionary.find("").is_none(), "{line}");
+        }
+    }
+
+    #[test]
+    fn leave_and_exit() {
+        let lines = [
+            ": first-over 10 0 do i over > if i . leave then loop drop ;",
+            "3 first-over 7 first-over",
+            ": find 5 0 do i 2 = if i unloop exit then loop -1 ;",
+            ": outer 3 0 do find . i . loop ;",
+            "outer",
+            ": early {: a :} a 0 < if 0 exit then a ;",
+            "-5 early . 6 early . depth .",
+            ": deep 1 if 2 if 3 else 4 then else 5 then ;",
+            "deep . 9 2 case 1 of 10 endof 2 of 20 endof 30 swap endcase . .",
+        ];
+        let result = get_output_from_lines(&lines);
+        assert_eq!(result.unwrap(), "482021220603209".to_string());
+
+        assert!(parse_line("leave".to_string(), &State::new()).is_err());
+        assert!(parse_line(": f 1 0 do ;".to_string(), &State::new()).is_err());
+    }
+
+    #[test]
+    fn exit_in_cpp_keeps_blocks_nested() {
+        let mut forth = Forth::without_prelude();
+        forth
+            .define("early", "{: a :} a 0 < if 0 exit then a")
+            .unwrap();
+        let cpp = forth.cpp_for_line("early").unwrap().unwrap();
+
+        // the return stays inside the if, and the locals block stays open after it
+        assert!(cpp.contains(
+            "if (pop(&stack) != 0) {\nstack.push_back(0);\nstd::cout << \" OK\\n\"; return 0;\n}\nstack.push_back(local_0);\n}\n"
+        ));
+        assert_eq!(cpp.matches('{').count(), cpp.matches('}').count());
+    }
+
+    #[test]
+    fn errors_unwind_loops_and_locals() {
+        let mut stack = Vec::new();
+        let mut state = State::new();
+        let mut buffer: Vec<u8> = Vec::new();
+        for line in [
+            ": g 3 0 do drop loop ;",
+            ": h {: a b :} a b + + ;",
+            "g",
+            "1 2 h",
+        ] {
+            _ = interpret_line(&mut stack, &mut state, line.to_string(), &mut buffer);
+        }
+        assert!(state.loop_control_stack.is_empty());
+        assert!(state.locals.is_empty());
+        assert!(state.locals_frames.is_empty());
+    }
+
+    #[test]
+    fn runaway_recursion_is_an_error() {
+        let result = get_output_from_lines(&[": down dup if -1 + down then ;", "100000 down"]);
+        let message = result.unwrap_err().to_string();
+        assert!(message.starts_with("Return Stack Overflow in down"));
+        assert!(message.ends_with("calls deep)"));
+    }
+
+    #[test]
+    fn return_depth_is_configurable() {
+        let mut forth = Forth::without_prelude();
+        forth.set_output(Box::new(Vec::new()));
+        forth.set_max_return_depth(200_000);
+        forth.eval(": down dup if -1 + down then ;").unwrap();
+        forth.eval("100000 down").unwrap();
+        assert_eq!(forth.stack(), &[0]);
+
+        forth.set_max_return_depth(10);
+        assert!(forth.eval("100 down").is_err());
+    }
+
+    fn effect_of(state: &State, name: &str) -> Option<(usize, usize, usize)> {
+        let index = state.dictionary.find_index(name).unwrap();
+        stack_effect::entry_effect(&state.dictionary, index)
+            .map(|e| (e.inputs, e.outputs, e.certain_inputs))
+    }
+
+    #[test]
+    fn stack_effects_are_inferred() {
+        let mut stack = Vec::new();
+        let mut state = State::new();
+        let mut buffer: Vec<u8> = Vec::new();
+        let lines = [
+            ": sq dup * ;",
+            ": pick-one if + else drop then ;",
+            ": uses sq pick-one ;",
+            ": fact dup 1 > if dup -1 + fact * then ;",
+            ": sum 0 swap 0 do i + loop ;",
+            ": unbalanced if 1 then ;",
+            ": runaway runaway ;",
+        ];
+        for l in lines {
+            interpret_line(&mut stack, &mut state, l.to_string(), &mut buffer).unwrap();
+        }
+
+        assert_eq!(effect_of(&state, "sq"), Some((1, 1, 1)));
+        // the else branch takes one fewer cell
+        assert_eq!(effect_of(&state, "pick-one"), Some((3, 1, 2)));
+        assert_eq!(effect_of(&state, "uses"), Some((3, 1, 2)));
+        assert_eq!(effect_of(&state, "fact"), Some((1, 1, 1)));
+        assert_eq!(effect_of(&state, "sum"), Some((1, 1, 1)));
+        assert_eq!(effect_of(&state, "unbalanced"), None);
+        assert_eq!(effect_of(&state, "runaway"), None);
+
+        // redefining a callee makes the effect unknown again
+        let line = ": sq dup ;".to_string();
+        interpret_line(&mut stack, &mut state, line, &mut buffer).unwrap();
+        assert_eq!(effect_of(&state, "uses"), None);
+    }
+
+    #[test]
+    fn definition_problems_are_warnings() {
+        let mut forth = Forth::without_prelude();
+        forth.set_output(Box::new(Vec::new()));
+        forth.eval(": unbalanced if 1 then ; : fine 1 ;").unwrap();
+        assert_eq!(
+            forth.take_warnings(),
+            vec!["unbalanced leaves the stack at different depths depending on the branch taken"]
+        );
+        assert!(forth.take_warnings().is_empty());
+        // the definition still runs
+        forth.eval("1 unbalanced").unwrap();
+        assert_eq!(forth.stack(), &[1]);
+    }
+
+    #[test]
+    fn certain_underflow_fails_before_running() {
+        let mut stack = Vec::new();
+        let mut state = State::new();
+        let mut buffer: Vec<u8> = Vec::new();
+        let line = ": f .\" hi \" + ; 1 f".to_string();
+        let err = interpret_line(&mut stack, &mut state, line, &mut buffer).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Stack Underflow, f takes at least 2 cells"));
+        assert!(buffer.is_empty());
+
+        // unoptimized it runs up to the underflow, as written
+        state.optimizer.optimizing = false;
+        let line = "f".to_string();
+        let err = interpret_line(&mut stack, &mut state, line, &mut buffer).unwrap_err();
+        assert_eq!(err.to_string(), "Stack Underflow at + in f (input:0)");
+        assert_eq!(buffer, b"hi");
+
+        // and verifying takes the two failures as the same
+        let mut forth = Forth::without_prelude();
+        forth.set_output(Box::new(Vec::new()));
+        forth.verify_optimizations().unwrap();
+        forth.eval(": f .\" hi \" + ;").unwrap();
+        let err = forth.eval("1 f").unwrap_err();
+        assert!(err.to_string().starts_with("Stack Underflow, f takes"));
+        forth.eval("2 3 f .").unwrap();
+
+        // a word that can run with fewer cells than it may take still does
+        let lines = [": pick-one if + else drop then ;", "6 0 pick-one depth ."];
+        assert_eq!(get_output_from_lines(&lines).unwrap(), "0");
+    }
+
+    #[test]
+    fn unchecked_words_give_the_same_results() {
+        let body = "2dup * -rot over swap rot nip 0 = 1+ swap 2drop 3 < 4 = dup 7 + mod 5 drop";
+        // run inside a word called with enough cells the depth checks are skipped, at the top
+        // level they aren't
+        let mut unchecked = Forth::without_prelude();
+        unchecked.eval(&format!(": shuffle {body} ;")).unwrap();
+        unchecked.eval("1 2 shuffle").unwrap();
+        let mut checked = Forth::without_prelude();
+        checked.eval(&format!("1 2 {body}")).unwrap();
+        assert_eq!(unchecked.stack(), checked.stack());
+    }
+
+    #[test]
+    fn executed_quotation_is_inlined() {
+        let words = parse_line("[: i if 2 then ;] execute 3 +".to_string(), &State::new()).unwrap();
+        assert_eq!(
+            words,
+            vec![
+                Word::I,
+                Word::If(4),
+                Word::Number(2),
+                Word::Then,
+                Word::Number(3),
+                Word::Plus
+            ]
+        )
+    }
+
+    #[test]
+    fn peephole_rules_apply_until_none_match() {
+        // swap drop becomes nip only once the quotation around it is spliced in
+        let words = parse_line(
+            "[: swap drop ;] execute i 1+ . cr".to_string(),
+            &State::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            words,
+            vec![
+                Word::Nip,
+                Word::IPlusConst(1),
+                Word::DotQuote("\n".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn peephole_rules_can_be_disabled() {
+        assert!(Forth::optimization_rules()
+            .iter()
+            .any(|rule| rule.starts_with("dup-mod-const: ")));
+        let mut forth = Forth::without_prelude();
+        assert!(forth.set_rule_enabled("no-such-rule", false).is_err());
+
+        let mut state = State::new();
+        state
+            .optimizer
+            .set_rule_enabled("dup-mod-const", false)
+            .unwrap();
+        let words = parse_line("dup 3 mod".to_string(), &state).unwrap();
+        assert_eq!(words, vec![Word::Dup, Word::Number(3), Word::Mod]);
+        // only for that interpreter
+        let words = parse_line("dup 3 mod".to_string(), &State::new()).unwrap();
+        assert_eq!(words, vec![Word::DupModConst(3)]);
+
+        state
+            .optimizer
+            .set_rule_enabled("dup-mod-const", true)
+            .unwrap();
+        let words = parse_line("dup 3 mod".to_string(), &state).unwrap();
+        assert_eq!(words, vec![Word::DupModConst(3)]);
+    }
+
+    #[test]
+    fn optimizations_can_be_turned_off() {
+        let mut state = State::new();
+        state.optimizer.optimizing = false;
+        let words = parse_line("swap drop".to_string(), &state).unwrap();
+        assert_eq!(words, vec![Word::Swap, Word::Drop]);
+
+        let mut forth = Forth::without_prelude();
+        forth.set_output(Box::new(Vec::new()));
+        forth.set_optimizing(false);
+        // run as written, so 0 + underflows, while other interpreters still rewrite it away
+        assert!(forth.eval("0 +").is_err());
+        assert!(Forth::without_prelude().eval("0 +").is_ok());
+    }
+
+    #[test]
+    fn verify_optimizations() {
+        let output = SharedOutput::default();
+        let mut forth = Forth::new();
+        forth.set_output(Box::new(output.clone()));
+        forth.set_input(Box::new(&b"ab"[..]));
+        forth
+            .register("seven", 0, 1, |vm| {
+                vm.push(7);
+                Ok(())
+            })
+            .unwrap();
+        forth.verify_optimizations().unwrap();
+        forth.eval(": f swap drop ; 1 2 f . seven 1 - .").unwrap();
+        assert_eq!(output.take(), "26");
+
+        // removing `0 +` hides the underflow it would have been
+        let message = forth.eval("0 +").unwrap_err().to_string();
+        assert!(
+            message.starts_with("Optimization changed the result of eval:1: succeeded optimized")
+        );
+        assert!(message.ends_with("rewrites made: zero-plus"));
+
+        // checking stops there, and the reference interpreter never took any keys
+        forth.eval("0 + key .").unwrap();
+        assert_eq!(output.take(), "97");
+    }
+
+    #[test]
+    fn constants_are_folded() {
+        let words = parse_line("3 4 * 12 = 10 3 mod 5 0 mod".to_string(), &State::new()).unwrap();
+        // dividing by zero is left to fail when it runs
+        assert_eq!(
+            words,
+            vec![
+                Word::Number(1),
+                Word::Number(1),
+                Word::Number(5),
+                Word::Number(0),
+                Word::Mod
+            ]
+        );
+        // and so is overflowing
+        let words = parse_line("9223372036854775807 1 + 2 3 +".to_string(), &State::new()).unwrap();
+        assert_eq!(
+            words,
+            vec![
+                Word::Number(i64::MAX),
+                Word::Number(1),
+                Word::Plus,
+                Word::Number(5)
+            ]
+        );
+    }
+
+    #[test]
+    fn constant_conditions_remove_dead_branches() {
+        let words = parse_line(
+            "0 if 1 else 2 3 < if 4 then then i 0 = if 5 then".to_string(),
+            &State::new(),
+        );
+        assert_eq!(
+            words.unwrap(),
+            vec![
+                Word::Number(4),
+                Word::I,
+                Word::NotIf(5),
+                Word::Number(5),
+                Word::Then
+            ]
+        );
+
+        // constants defined before a word is first run are folded into it
+        assert_eq!(
+            get_output_from_lines(&[
+                "2 constant two",
+                ": f two 3 * 6 = if 1 . else 2 . then ;",
+                "f",
+            ])
+            .unwrap(),
+            "1"
+        );
+    }
+
+    fn state_with_prelude() -> State {
+        let mut state = State::new();
+        prelude(&mut Vec::new(), &mut state).unwrap();
+        state
+    }
+
+    #[test]
+    fn prelude_words() {
+        let lines = [
+            "7 3 - . 7 2 / . -5 abs . 3 9 min . 3 9 max . 7 3 /mod . .",
+            "65 emit 3 spaces 66 emit 0 spaces 67 emit",
+            "variable v 5 v +! 5 v +! v @ . 0 0= . 1 2 <> .",
+        ];
+        let result = get_output_from_lines_with(state_with_prelude(), &lines);
+        assert_eq!(result.unwrap(), "4353921A   BC1011".to_string())
+    }
+
+    #[test]
+    fn prelude_words_are_flattened() {
+        let state = state_with_prelude();
+        let words = &state.dictionary.colon("spaces").unwrap().words;
+        assert!(!words.iter().any(|w| matches!(w, Word::Word(_))));
+
+        // and still follow redefinitions, like any other inlined word
+        let lines = [": bl 46 ; 2 spaces", ": - + ; 1 1 - ."];
+        let result = get_output_from_lines_with(state, &lines);
+        assert_eq!(result.unwrap(), "..2".to_string());
+
+        // however big the word they are called from
+        let state = state_with_prelude();
+        let big = vec!["dup drop"; 300].join(" ");
+        let words = parse_line(format!("{big} 1 -"), &state).unwrap();
+        let (inlined, depends) = parsing::inline_function(&"f".to_string(), &words, &state);
+        assert!(!inlined
+            .iter()
+            .any(|w| matches!(w, Word::Word(_) | Word::Call(_))));
+        assert!(depends.contains("-"));
+    }
+
+    fn state_with_keys(keys: &[u8], ansi: bool) -> State {
+        let mut state = State::new();
+        state.terminal = Terminal::new(KeySource::Bytes(keys.to_vec()), ansi);
+        state
+    }
+
+    #[test]
+    fn key_words() {
+        let state = state_with_keys(b"ab\x1b[Ax", false);
+        let result =
+            get_output_from_lines_with(state, &["key? . key . key . ekey . ekey . key? ."]);
+        assert_eq!(result.unwrap(), "1979817928331200".to_string())
+    }
+
+    #[test]
+    fn accept_line() {
+        let state = state_with_keys(b"hello\nh\x7fhi\n", false);
+        let lines = [
+            "create buf 10 allot",
+            "buf 3 accept . buf 3 type buf 10 accept . buf 2 type buf 5 accept .",
+        ];
+        let result = get_output_from_lines_with(state, &lines);
+        assert_eq!(result.unwrap(), "3hel2hi0".to_string())
+    }
+
+    #[test]
+    fn cursor_words() {
+        let result = get_output_from_lines_with(state_with_keys(b"", true), &["3 5 at-xy page"]);
+        assert_eq!(result.unwrap(), "\x1b[6;4H\x1b[2J\x1b[H".to_string());
+
+        // nothing but a page break when the output isn't a terminal
+        let result = get_output_from_lines_with(state_with_keys(b"", false), &["3 5 at-xy page"]);
+        assert_eq!(result.unwrap(), "\n".to_string())
+    }
+
+    #[test]
+    fn virtual_clock() {
+        let mut state = State::new();
+        state.clock = Clock::new_virtual();
+        let lines = [
+            "ticks . 1500 ms ticks . utime . utime-ns .",
+            "time&date . . . . . .",
+            "1709210094500 ms time&date . . . . . .",
+        ];
+        let result = get_output_from_lines_with(state, &lines);
+        assert_eq!(
+            result.unwrap(),
+            "01500150000015000000001970110012024229123456".to_string()
+        )
+    }
+
+    // an output the test can still read after handing it to a Forth
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedOutput {
+        fn take(&self) -> String {
+            String::from_utf8(self.0.take()).unwrap()
+        }
+    }
+
+    #[test]
+    fn embedding_api() {
+        let output = SharedOutput::default();
+        let mut forth = Forth::new();
+        forth.set_output(Box::new(output.clone()));
+
+        forth.eval(": sq dup * ;\n3 sq .").unwrap();
+        assert_eq!(output.take(), "9");
+
+        forth.push(4);
+        forth.push(5);
+        forth.call_word("SQ").unwrap();
+        assert_eq!(forth.stack(), &[4, 25]);
+        forth.call_word("+").unwrap();
+        assert_eq!(forth.pop(), Some(29));
+        assert_eq!(forth.pop(), None);
+
+        forth.define("greet", ".\" hi \" abs .").unwrap();
+        forth.push(-7);
+        forth.call_word("greet").unwrap();
+        assert_eq!(output.take(), "hi7");
+
+        forth.define("abs", "drop 7").unwrap();
+        forth.eval("-3 abs .").unwrap();
+        assert_eq!(output.take(), "7");
+
+        assert!(forth.define("two words", "1").is_err());
+        assert!(forth.call_word("missing").is_err());
+        // errors name the word that failed, and leave the interpreter usable
+        let err = forth.eval(": f dup if + then ; drop").unwrap_err();
+        assert_eq!(err.to_string(), "Stack Underflow at drop");
+        let err = forth.eval("5 f").unwrap_err();
+        assert_eq!(err.to_string(), "Stack Underflow at + in f (eval:1)");
+        forth.eval("1 2 + .").unwrap();
+        assert_eq!(output.take(), "3");
+
+        // and so do errors inside loops and locals frames
+        forth.eval(": g 3 0 do drop loop ;").unwrap();
+        assert!(forth.eval("g").is_err());
+        assert!(forth.eval("i .").is_err());
+        forth.eval(": h {: a b :} a b + @ ;").unwrap();
+        assert!(forth.eval("1 2 h").is_err());
+        assert_eq!(forth.stack(), &[] as &[i64]);
+        forth
+            .eval(": k {: x y :} x y - ; 10 3 k . 4 1 k .")
+            .unwrap();
+        assert_eq!(output.take(), "73");
+    }
+
+    #[test]
+    fn embedding_input() {
+        let output = SharedOutput::default();
+        let mut forth = Forth::without_prelude();
+        forth.set_output(Box::new(output.clone()));
+        forth.set_input(Box::new(&b"ab"[..]));
+
+        forth.eval("key key emit emit").unwrap();
+        assert_eq!(output.take(), "ba");
+        // the prelude's words aren't there
+        assert!(forth.eval("1 negate").is_err());
+    }
+
+    #[test]
+    fn host_words() {
+        let output = SharedOutput::default();
+        let mut forth = Forth::new();
+        forth.set_output(Box::new(output.clone()));
+
+        let reading = Rc::new(RefCell::new(20));
+        let sensor = reading.clone();
+        forth
+            .register("Sensor@", 0, 1, move |vm| {
+                vm.push(*sensor.borrow());
+                Ok(())
+            })
+            .unwrap();
+        forth
+            .register("show", 1, 0, |vm| {
+                let value = vm.pop()?;
+                write!(vm.output(), "<{value}>")?;
+                Ok(())
+            })
+            .unwrap();
+
+        // called from colon definitions, inlined or not, they still run the host code
+        forth.eval(": twice sensor@ 2 * show ;").unwrap();
+        *reading.borrow_mut() = 21;
+        forth.eval("twice twice ' sensor@ execute show").unwrap();
+        assert_eq!(output.take(), "<42><42><21>");
+
+        // redefining one is seen by words already using it
+        forth
+            .register("sensor@", 0, 1, |vm| {
+                vm.push(5);
+                Ok(())
+            })
+            .unwrap();
+        forth.eval("twice").unwrap();
+        assert_eq!(output.take(), "<10>");
+
+        forth.register("bad", 0, 1, |_| Ok(())).unwrap();
+        let err = forth.eval("bad").unwrap_err();
+        assert_eq!(err.to_string(), "Left the stack 0 deep instead of 1 at bad");
+
+        // a word can't reach below the inputs it was registered with
+        forth
+            .register("greedy", 1, 1, |vm| {
+                let top = vm.pop()?;
+                let below = vm.pop()?;
+                vm.push(top + below);
+                Ok(())
+            })
+            .unwrap();
+        let err = forth.eval("1 2 greedy").unwrap_err();
+        assert_eq!(err.to_string(), "Stack Underflow at greedy");
+        assert_eq!(forth.stack(), &[1]);
+        forth.eval("drop").unwrap();
+        assert!(forth.eval("show").is_err());
+        assert_eq!(forth.stack(), &[] as &[i64]);
+    }
 }
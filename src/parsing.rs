@@ -1,8 +1,10 @@
-use crate::{DefinedWord, State};
-use ahash::{HashSet, HashSetExt, RandomState};
-use std::collections::HashMap;
-use std::str::FromStr;
+use crate::dictionary::{Dictionary, DictionaryEntry, EntryKind};
+use crate::memory::Memory;
+use crate::optimizer::optimization_pass;
+use crate::State;
+use ahash::{HashSet, HashSetExt};
 
+#[allow(clippy::enum_variant_names)]
 #[derive(PartialEq, Debug, Hash, Eq, Clone)]
 pub(crate) enum Word {
     Dot,
@@ -12,13 +14,46 @@ pub(crate) enum Word {
     // name of var/const
     Variable(String),
     Constant(String),
+    Value(String),
+    TwoVariable(String),
+    TwoConstant(String),
+    TwoValue(String),
+    Buffer(String),
+    Create(String),
+    BeginStructure(String),
+    // name of the field, the size comes from the word
+    PlusField(String),
+    FieldColon(String),
+    CFieldColon(String),
+    FFieldColon(String),
+    // name whose execution token is pushed
+    Tick(String),
+    // name being checked
+    Defined(String),
+    Undefined(String),
+    // name of the value being set
+    To(String),
+    PlusTo(String),
+    EndStructure,
     Loop,
     Do,
-    // relative offset to else or then
+    // index just after the innermost loop
+    Leave(usize),
+    // drops the innermost loop, so exit can be used inside one
+    Unloop,
+    // returns from the word being run
+    Exit,
+    // index to jump to when false, just after the else or then
     If(usize),
-    // relative offset to end of statement
+    // index to jump to when the if part is done, just after the then
     Else(usize),
     Then,
+    Case,
+    // index to jump to when it doesn't match, just after the matching endof
+    Of(usize),
+    // index just after the endcase
+    EndOf(usize),
+    EndCase,
     Plus,
     Cr,
     UDotR,
@@ -34,21 +69,95 @@ pub(crate) enum Word {
     Swap,
     Rot,
     Exclamation,
+    TwoAt,
+    TwoExclamation,
+    Cells,
+    Comma,
+    Allot,
+    Here,
+    Allocate,
+    Free,
+    Resize,
+    Move,
+    Fill,
+    Erase,
+    Compare,
+    Search,
+    SlashString,
+    MinusTrailing,
+    Blank,
+    CMove,
+    CMoveUp,
+    SLiteral,
+    Replaces,
+    Substitute,
+    Block,
+    // the block word set's buffer, named so it isn't confused with buffer:
+    BlockBuffer,
+    Update,
+    SaveBuffers,
+    Flush,
+    Load,
+    Thru,
+    List,
+    Scr,
     I,
     J,
     Reset,
     OnePlus,
     PlusLoop,
+    Over,
+    Nip,
+    Tuck,
+    Pick,
+    Roll,
+    QuestionDup,
+    Depth,
+    TwoDup,
+    TwoDrop,
+    TwoSwap,
+    TwoOver,
+    TwoRot,
+    Type,
+    Emit,
+    Key,
+    KeyQuestion,
+    Ekey,
+    Accept,
+    AtXy,
+    Page,
+    Ms,
+    TimeAndDate,
+    Utime,
+    UtimeNs,
+    Ticks,
+    Evaluate,
+    SourceId,
 
     // word is a general placeholder, may be a variable, constant, function call, etc.
     Word(String),
+    // a word resolved to its interned symbol, so calling it doesn't hash its name
+    Call(usize),
     // numbers. Won't be parsed by from_string
     Number(i64),
     // quote. really means ." and "
     Quote(String),
+    // string literal. really means s" and ", pushes the address and length
+    StringLiteral(String),
+    // a string literal given its own copy in data space: the address, and the text for the c++ output
+    PlacedString(i64, String),
+    // start of a locals frame: number of locals taken from the stack, then number of zeroed locals
+    Locals(usize, usize),
+    // index into the current locals frame
+    LocalFetch(usize),
+    LocalStore(usize),
+    EndLocals,
+    // [: ... ;], pushes the execution token of its already parsed body
+    Quotation(Vec<Word>),
+    Execute,
 
     //special optimizations
-    DoubleRot,
+    DoubleRot, // also what -rot parses to
     EqZero,
     NotIf(usize),
     DupModConst(i64),
@@ -57,49 +166,205 @@ pub(crate) enum Word {
     IPlusConst(i64), // equivilant of I <const> + or <const> I +
 }
 
-impl FromStr for Word {
-    type Err = ();
-
-    fn from_str(input: &str) -> Result<Word, Self::Err> {
-        match input.to_lowercase().as_str() {
-            // needs to be filled in later
-            ":" => Ok(Word::Function(String::new())),
-            ";" => Ok(Word::EndFunction),
-            "variable" => Ok(Word::Variable(String::new())),
-            "constant" => Ok(Word::Constant(String::new())),
-            "if" => Ok(Word::If(0)),
-            "else" => Ok(Word::Else(0)),
-
-            "loop" => Ok(Word::Loop),
-            "do" => Ok(Word::Do),
-            "." => Ok(Word::Dot),
-            "then" => Ok(Word::Then),
-            "+" => Ok(Word::Plus),
-            "cr" => Ok(Word::Cr),
-            "u.r" => Ok(Word::UDotR),
-            "=" => Ok(Word::Equal),
-            ">" => Ok(Word::Greater),
-            "<" => Ok(Word::Less),
-            "*/" => Ok(Word::MultDivide),
-            "mod" => Ok(Word::Mod),
-            "*" => Ok(Word::Mult),
-            "@" => Ok(Word::At),
-            "dup" => Ok(Word::Dup),
-            "drop" => Ok(Word::Drop),
-            "swap" => Ok(Word::Swap),
-            "rot" => Ok(Word::Rot),
-            "!" => Ok(Word::Exclamation),
-            "i" => Ok(Word::I),
-            "j" => Ok(Word::J),
-            "reset" => Ok(Word::Reset),
-            "1+" => Ok(Word::OnePlus),
-            "+loop" => Ok(Word::PlusLoop),
-            _ => Ok(Word::Word(input.to_string().clone())),
+// every builtin word, by the (lower case) name it is parsed from
+// words which take a name or an offset get it filled in later
+pub(crate) const BUILTIN_WORDS: &[(&str, Word)] = &[
+    (":", Word::Function(String::new())),
+    (";", Word::EndFunction),
+    ("variable", Word::Variable(String::new())),
+    ("constant", Word::Constant(String::new())),
+    ("value", Word::Value(String::new())),
+    ("2variable", Word::TwoVariable(String::new())),
+    ("2constant", Word::TwoConstant(String::new())),
+    ("2value", Word::TwoValue(String::new())),
+    ("buffer:", Word::Buffer(String::new())),
+    ("create", Word::Create(String::new())),
+    ("begin-structure", Word::BeginStructure(String::new())),
+    ("+field", Word::PlusField(String::new())),
+    ("field:", Word::FieldColon(String::new())),
+    ("cfield:", Word::CFieldColon(String::new())),
+    ("ffield:", Word::FFieldColon(String::new())),
+    ("end-structure", Word::EndStructure),
+    ("'", Word::Tick(String::new())),
+    ("execute", Word::Execute),
+    ("[defined]", Word::Defined(String::new())),
+    ("[undefined]", Word::Undefined(String::new())),
+    ("to", Word::To(String::new())),
+    ("+to", Word::PlusTo(String::new())),
+    ("if", Word::If(0)),
+    ("else", Word::Else(0)),
+    ("loop", Word::Loop),
+    ("do", Word::Do),
+    ("leave", Word::Leave(0)),
+    ("unloop", Word::Unloop),
+    ("exit", Word::Exit),
+    (".", Word::Dot),
+    ("then", Word::Then),
+    ("case", Word::Case),
+    ("of", Word::Of(0)),
+    ("endof", Word::EndOf(0)),
+    ("endcase", Word::EndCase),
+    ("+", Word::Plus),
+    ("cr", Word::Cr),
+    ("u.r", Word::UDotR),
+    ("=", Word::Equal),
+    (">", Word::Greater),
+    ("<", Word::Less),
+    ("*/", Word::MultDivide),
+    ("mod", Word::Mod),
+    ("*", Word::Mult),
+    ("@", Word::At),
+    ("dup", Word::Dup),
+    ("drop", Word::Drop),
+    ("swap", Word::Swap),
+    ("rot", Word::Rot),
+    ("-rot", Word::DoubleRot),
+    ("over", Word::Over),
+    ("nip", Word::Nip),
+    ("tuck", Word::Tuck),
+    ("pick", Word::Pick),
+    ("roll", Word::Roll),
+    ("?dup", Word::QuestionDup),
+    ("depth", Word::Depth),
+    ("2dup", Word::TwoDup),
+    ("2drop", Word::TwoDrop),
+    ("2swap", Word::TwoSwap),
+    ("2over", Word::TwoOver),
+    ("2rot", Word::TwoRot),
+    ("!", Word::Exclamation),
+    ("2@", Word::TwoAt),
+    ("2!", Word::TwoExclamation),
+    ("cells", Word::Cells),
+    (",", Word::Comma),
+    ("allot", Word::Allot),
+    ("here", Word::Here),
+    ("allocate", Word::Allocate),
+    ("free", Word::Free),
+    ("resize", Word::Resize),
+    ("move", Word::Move),
+    ("fill", Word::Fill),
+    ("erase", Word::Erase),
+    ("compare", Word::Compare),
+    ("search", Word::Search),
+    ("/string", Word::SlashString),
+    ("-trailing", Word::MinusTrailing),
+    ("blank", Word::Blank),
+    ("cmove", Word::CMove),
+    ("cmove>", Word::CMoveUp),
+    ("sliteral", Word::SLiteral),
+    ("replaces", Word::Replaces),
+    ("substitute", Word::Substitute),
+    ("block", Word::Block),
+    ("buffer", Word::BlockBuffer),
+    ("update", Word::Update),
+    ("save-buffers", Word::SaveBuffers),
+    ("flush", Word::Flush),
+    ("load", Word::Load),
+    ("thru", Word::Thru),
+    ("list", Word::List),
+    ("scr", Word::Scr),
+    ("i", Word::I),
+    ("j", Word::J),
+    ("reset", Word::Reset),
+    ("1+", Word::OnePlus),
+    ("+loop", Word::PlusLoop),
+    ("type", Word::Type),
+    ("emit", Word::Emit),
+    ("key", Word::Key),
+    ("key?", Word::KeyQuestion),
+    ("ekey", Word::Ekey),
+    ("accept", Word::Accept),
+    ("at-xy", Word::AtXy),
+    ("page", Word::Page),
+    ("ms", Word::Ms),
+    ("time&date", Word::TimeAndDate),
+    ("utime", Word::Utime),
+    ("utime-ns", Word::UtimeNs),
+    ("ticks", Word::Ticks),
+    ("evaluate", Word::Evaluate),
+    ("source-id", Word::SourceId),
+];
+
+impl Word {
+    // what a name means to the parser, looked up in the dictionary so a definition shadows a builtin
+    // names defined earlier on the same line aren't in the dictionary yet, so they are passed in
+    fn resolve(name: &str, dictionary: &Dictionary, defined_here: &[String]) -> Word {
+        if defined_here.iter().any(|defined| defined == name) {
+            return Word::Word(name.to_string());
+        }
+        match dictionary.find(name) {
+            Some(DictionaryEntry {
+                kind: EntryKind::Native(word),
+                ..
+            }) => word.clone(),
+            _ => Word::Word(name.to_string()),
+        }
+    }
+
+    // takes a name and makes a new definition with it, unlike tick or to
+    fn defines_name(&self) -> bool {
+        self.takes_name()
+            && !matches!(
+                self,
+                Word::Tick(_)
+                    | Word::Defined(_)
+                    | Word::Undefined(_)
+                    | Word::To(_)
+                    | Word::PlusTo(_)
+            )
+    }
+
+    fn takes_name(&self) -> bool {
+        matches!(
+            self,
+            Word::Function(_)
+                | Word::Variable(_)
+                | Word::Constant(_)
+                | Word::Value(_)
+                | Word::TwoVariable(_)
+                | Word::TwoConstant(_)
+                | Word::TwoValue(_)
+                | Word::Buffer(_)
+                | Word::Create(_)
+                | Word::BeginStructure(_)
+                | Word::PlusField(_)
+                | Word::FieldColon(_)
+                | Word::CFieldColon(_)
+                | Word::FFieldColon(_)
+                | Word::Tick(_)
+                | Word::Defined(_)
+                | Word::Undefined(_)
+                | Word::To(_)
+                | Word::PlusTo(_)
+        )
+    }
+
+    // copy of a word that takes a name, with the name filled in
+    fn with_name(&self, name: String) -> Word {
+        match self {
+            Word::Function(_) => Word::Function(name),
+            Word::Variable(_) => Word::Variable(name),
+            Word::Constant(_) => Word::Constant(name),
+            Word::Value(_) => Word::Value(name),
+            Word::TwoVariable(_) => Word::TwoVariable(name),
+            Word::TwoConstant(_) => Word::TwoConstant(name),
+            Word::TwoValue(_) => Word::TwoValue(name),
+            Word::Buffer(_) => Word::Buffer(name),
+            Word::Create(_) => Word::Create(name),
+            Word::BeginStructure(_) => Word::BeginStructure(name),
+            Word::PlusField(_) => Word::PlusField(name),
+            Word::FieldColon(_) => Word::FieldColon(name),
+            Word::CFieldColon(_) => Word::CFieldColon(name),
+            Word::FFieldColon(_) => Word::FFieldColon(name),
+            Word::Tick(_) => Word::Tick(name),
+            Word::Defined(_) => Word::Defined(name),
+            Word::Undefined(_) => Word::Undefined(name),
+            Word::To(_) => Word::To(name),
+            Word::PlusTo(_) => Word::PlusTo(name),
+            _ => self.clone(),
         }
     }
-}
 
-impl Word {
     fn new_quote(str: String) -> Word {
         Word::Quote(str)
     }
@@ -109,12 +374,28 @@ impl Word {
     }
 }
 
-pub(crate) fn parse_line(line: String) -> Result<Vec<Word>, String> {
+pub(crate) fn parse_line(line: String, state: &State) -> Result<Vec<Word>, String> {
+    parse_words(line, state, &mut Vec::new())
+}
+
+// `defined_here` collects the names defined on this line so far, which later words must call
+fn parse_words(
+    line: String,
+    state: &State,
+    defined_here: &mut Vec<String>,
+) -> Result<Vec<Word>, String> {
     //parse numbers, quotes and the individual words
     // then go back and add the additional data (and compress function declarations, etc)
     let words: Vec<&str> = line.split(' ').collect();
     let mut out_words: Vec<Word> = Vec::with_capacity(words.len());
 
+    // names declared with {: ... :} in the definition currently being parsed
+    let mut locals: Vec<String> = Vec::new();
+    // the word after a defining word (or to) is a name, even if it is also a builtin (like over)
+    let mut expecting_name = false;
+    // a colon definition's name only becomes visible at its ;
+    let mut colon_name: Option<String> = None;
+
     let mut i = 0;
 
     while i < words.len() {
@@ -124,19 +405,91 @@ pub(crate) fn parse_line(line: String) -> Result<Vec<Word>, String> {
             break;
         }
 
-        if word == ".\"" {
+        if expecting_name {
+            match out_words.last() {
+                Some(Word::Function(_)) => colon_name = Some(word.to_string()),
+                Some(defining) if defining.defines_name() => defined_here.push(word.to_string()),
+                _ => {}
+            }
+            out_words.push(Word::Word(word.to_string()));
+            expecting_name = false;
+            i += 1;
+            continue;
+        }
+
+        match word {
+            ":" => locals.clear(),
+            ";" if !locals.is_empty() => {
+                // release the locals frame before leaving the definition
+                out_words.push(Word::EndLocals);
+                locals.clear();
+            }
+            // exit skips the end of the definition, so it has to release the frame itself
+            "exit" if !locals.is_empty() => out_words.push(Word::EndLocals),
+            _ => {}
+        }
+        if word == ";" {
+            defined_here.extend(colon_name.take());
+        }
+
+        if is_quote_start(word) {
             // now find the end, and print the whole thing
             let quote_last_index = skip_quote(i, &words);
 
             // grab the words between i and quote index, then concat and add to output
             let out = &words[i + 1..quote_last_index - 1].join(" ");
 
-            out_words.push(Word::new_quote((*out).clone()));
+            if word == ".\"" {
+                out_words.push(Word::new_quote((*out).clone()));
+            } else {
+                out_words.push(Word::StringLiteral((*out).clone()));
+            }
 
             i = quote_last_index;
             continue;
         }
 
+        if word == "[:" {
+            let end = find_quotation_end(i, &words)?;
+            // parsed on its own, so its locals and control flow don't mix with the enclosing definition's
+            let mut body = parse_words(words[i + 1..end].join(" "), state, defined_here)?;
+            if body.iter().any(|w| matches!(w, Word::Locals(..))) {
+                body.push(Word::EndLocals);
+            }
+            out_words.push(Word::Quotation(body));
+
+            i = end + 1;
+            continue;
+        }
+
+        if word == "{:" {
+            if !locals.is_empty() {
+                return Err("Only one locals declaration allowed per definition".to_string());
+            }
+            let (args, uninitialized, next_index) = parse_locals(i, &words, &mut locals)?;
+            out_words.push(Word::Locals(args, uninitialized));
+
+            i = next_index;
+            continue;
+        }
+
+        if let Some(index) = locals.iter().position(|l| l == word) {
+            out_words.push(Word::LocalFetch(index));
+            i += 1;
+            continue;
+        }
+
+        if word == "to" {
+            let target = words
+                .get(i + 1)
+                .and_then(|name| locals.iter().position(|l| l == name));
+            if let Some(index) = target {
+                out_words.push(Word::LocalStore(index));
+                i += 2;
+                continue;
+            }
+        }
+
         let int = word.parse::<i64>();
         if let Ok(val) = int {
             out_words.push(Word::new_num(val));
@@ -168,295 +521,303 @@ pub(crate) fn parse_line(line: String) -> Result<Vec<Word>, String> {
             }
         }
 
-        let parsed = Word::from_str(word);
-        if let Ok(parsed_word) = parsed {
-            out_words.push(parsed_word);
-
+        let parsed_word = Word::resolve(word, &state.dictionary, defined_here);
+        if parsed_word == Word::SLiteral {
+            // every string literal already gets its own copy when the line is placed, once however
+            // often it runs, so sliteral only has to check it has one to compile
+            if !matches!(out_words.last(), Some(Word::StringLiteral(_))) {
+                return Err("sliteral needs a string literal before it".to_string());
+            }
             i += 1;
             continue;
-        } else {
-            return Err("Could not parse word".to_string());
         }
+        expecting_name = parsed_word.takes_name();
+        out_words.push(parsed_word);
+        i += 1;
     }
 
-    // second pass figures out if, else, and function declarations
+    // second pass attaches names to the defining words (and to) that take one
     let mut i = 0;
     while i < out_words.len() {
         let word = out_words.get(i).unwrap();
-        match word {
-            Word::If(_) => {
-                //todo!("Look for else or then and store relative offset")
-                let mut if_index = i + 1;
-                let mut nested = 0;
-                let mut if_word = out_words.get(if_index).unwrap();
-                while if_index < out_words.len()
-                    && (!(matches!(if_word, Word::Else(_)) || matches!(if_word, Word::Then))
-                        || nested > 0)
-                {
-                    // then we need to deal with a nested if scenario
-                    if matches!(if_word, Word::If(_)) {
-                        nested += 1;
-                    }
-                    if matches!(if_word, Word::Then) {
-                        nested -= 1;
-                    }
-
-                    if_index += 1;
-                    if_word = out_words.get(if_index).unwrap()
-                }
-
-                if if_index >= out_words.len() {
-                    return Err("No closing else or then".to_string());
-                }
-
-                out_words[i] = Word::If(if_index - 1 - i);
-            }
-            Word::Else(_) => {
-                //todo!("Look for then and store relative offset")
-                let mut else_index = i + 1;
-                let mut nested = 0;
-                let mut else_word = out_words.get(else_index).unwrap();
-                while else_index < out_words.len()
-                    && (!matches!(else_word, Word::Then) || nested > 0)
-                {
-                    // then we need to deal with a nested if scenario
-                    if matches!(else_word, Word::If(_)) {
-                        nested += 1;
-                    }
-                    if matches!(else_word, Word::Then) {
-                        nested -= 1;
-                    }
-
-                    else_index += 1;
-                    else_word = out_words.get(else_index).unwrap()
-                }
-
-                if else_index >= out_words.len() {
-                    return Err("No closing else or then".to_string());
+        if word.takes_name() {
+            match out_words.get(i + 1) {
+                Some(Word::Word(x)) => {
+                    out_words[i] = word.with_name(x.clone());
+                    out_words.remove(i + 1);
                 }
-
-                out_words[i] = Word::Else(else_index - 1 - i);
-            }
-            Word::Function(_) => {
-                let next = out_words.get(i + 1).unwrap();
-                match next {
-                    Word::Word(x) => {
-                        out_words[i] = Word::Function((*x).clone());
-                        out_words.remove(i + 1);
-                    }
-                    _ => {
-                        return Err("Expected word after :".to_string());
-                    }
+                _ => {
+                    return Err(format!("Expected word after {word:?}"));
                 }
             }
-            Word::Variable(_) => {
-                let next = out_words.get(i + 1).unwrap();
-                match next {
-                    Word::Word(x) => {
-                        out_words[i] = Word::Variable((*x).clone());
-                        out_words.remove(i + 1);
-                    }
-                    _ => {
-                        return Err("Expected word after variable".to_string());
-                    }
-                }
-            }
-            Word::Constant(_) => {
-                let next = out_words.get(i + 1).unwrap();
-                match next {
-                    Word::Word(x) => {
-                        out_words[i] = Word::Constant((*x).clone());
-                        out_words.remove(i + 1);
-                    }
-                    _ => {
-                        return Err("Expected word after constant".to_string());
-                    }
-                }
-            }
-            _ => {
-                // do nothing on default case
-            }
         }
 
         i += 1;
     }
 
     // third pass does optimizations
-    optimization_pass(&mut out_words);
+    optimization_pass(&mut out_words, &state.optimizer);
+
+    // last pass resolves the jump offsets, now that nothing will move around anymore
+    resolve_control_flow(&mut out_words)?;
 
     Ok(out_words)
 }
 
-pub(crate) fn optimization_pass(out_words: &mut Vec<Word>) {
-    let mut i = 0;
-    while i < out_words.len() {
-        let word = out_words.get(i).unwrap();
+// replaces every name to be looked up with its symbol, once per line instead of once per call
+// the symbol still goes through the dictionary when run, so redefinitions are seen
+pub(crate) fn intern_calls(words: &mut [Word], dictionary: &mut Dictionary) {
+    for word in words.iter_mut() {
         match word {
-            Word::Rot => {
-                if let Some(next) = out_words.get(i + 1) {
-                    if next == &Word::Rot {
-                        out_words[i] = Word::DoubleRot;
-                        out_words.remove(i + 1);
-                    }
-                }
-            }
-            Word::Number(0) => {
-                let mut next = out_words.get(i + 1).unwrap();
-                if next == &Word::Equal {
-                    next = out_words.get(i + 2).unwrap();
-                    if let Word::If(val) = next {
-                        // if it is `0 = if`, then do notEquals optimization
-                        out_words[i] = Word::NotIf(*val);
-                        out_words.remove(i + 1);
-                        out_words.remove(i + 1); //remove the extraneous operations
-                    } else {
-                        // if it isn't `0 = if`, then just do the eq zero optimization
-                        out_words[i] = Word::EqZero;
-                        out_words.remove(i + 1);
-                    }
-                }
-            }
-            Word::Number(constant) => {
-                let mut next = out_words.get(i + 1).unwrap();
-                if next == &Word::PlusLoop {
-                    // optimize <const> LOOP+
-                    out_words[i] = Word::PlusLoopConst(*constant);
-                    out_words.remove(i + 1);
-                } else if next == &Word::I {
-                    next = out_words.get(i + 2).unwrap();
-                    if next == &Word::Plus {
-                        // if it is `<const> I +`, then do IPlusConst optimization
-                        out_words[i] = Word::IPlusConst(*constant);
-                        out_words.remove(i + 1);
-                        out_words.remove(i + 1); //remove the extraneous operations
-                    }
-                }
-            }
-            Word::Dup => {
-                // handle dup, number, mod (and eventually other operations)
-                let mut next = out_words.get(i + 1).unwrap();
-                if let Word::Number(val) = next {
-                    next = out_words.get(i + 2).unwrap();
-                    if next == &Word::Mod {
-                        // if it is `dup x mod`, then do a constant mod optimization
-                        out_words[i] = Word::DupModConst(*val);
-                        out_words.remove(i + 1);
-                        out_words.remove(i + 1); //remove the extraneous operations
-                    }
-                }
+            Word::Word(name) => *word = Word::Call(dictionary.intern(name)),
+            Word::Quotation(body) => intern_calls(body, dictionary),
+            _ => {}
+        }
+    }
+}
+
+// copies each string literal into data space, so every occurrence has an address of its own
+// that stays the same however often it runs, and writing through it changes no other literal
+pub(crate) fn place_strings(words: &mut [Word], memory: &mut Memory) {
+    for word in words.iter_mut() {
+        match word {
+            Word::StringLiteral(text) => {
+                *word = Word::PlacedString(memory.store_bytes(text.as_bytes()), text.clone())
             }
-            Word::Dot => {
-                if let Some(replace) = inline_strings(out_words, i, String::new()) {
-                    out_words[i] = Word::DotQuote(replace);
-                }
+            Word::Quotation(body) => place_strings(body, memory),
+            _ => {}
+        }
+    }
+}
+
+// fills in the jump targets for if, else, of, endof and leave in a single pass
+// targets are indexes into `words`, so this must be rerun after anything which adds or removes words
+// (optimizations and inlining) and on a definition's words once they are copied out of the line
+pub(crate) fn resolve_control_flow(words: &mut [Word]) -> Result<(), String> {
+    // indexes of the control words still waiting for their closing word
+    let mut open: Vec<usize> = Vec::with_capacity(8);
+    // the leaves inside each open do, which jump past its loop
+    let mut loops: Vec<Vec<usize>> = Vec::with_capacity(4);
+
+    for i in 0..words.len() {
+        match words[i] {
+            Word::If(_) | Word::NotIf(_) | Word::Case | Word::Of(_) => open.push(i),
+            Word::Else(_) => {
+                let j = open.pop().ok_or("Else without if")?;
+                // a false if starts running just after the else
+                words[j] = match words[j] {
+                    Word::If(_) => Word::If(i + 1),
+                    Word::NotIf(_) => Word::NotIf(i + 1),
+                    _ => return Err("Else without if".to_string()),
+                };
+                open.push(i);
             }
-            Word::Quote(val) => {
-                if let Some(replace) = inline_strings(out_words, i, val.clone()) {
-                    out_words[i] = Word::Quote(replace);
-                }
+            Word::Then => {
+                let j = open.pop().ok_or("Then without if")?;
+                words[j] = match words[j] {
+                    Word::If(_) => Word::If(i + 1),
+                    Word::NotIf(_) => Word::NotIf(i + 1),
+                    Word::Else(_) => Word::Else(i + 1),
+                    _ => return Err("Then without if".to_string()),
+                };
             }
-            Word::DotQuote(val) => {
-                if let Some(replace) = inline_strings(out_words, i, val.clone()) {
-                    out_words[i] = Word::DotQuote(replace);
+            Word::EndOf(_) => {
+                let j = open.pop().ok_or("Endof without of")?;
+                if !matches!(words[j], Word::Of(_)) {
+                    return Err("Endof without of".to_string());
                 }
+                // a failed of continues right after its endof
+                words[j] = Word::Of(i + 1);
+                // the endof itself waits for endcase
+                open.push(i);
             }
-            Word::I => {
-                let mut next = out_words.get(i + 1).unwrap();
-                if next == &Word::OnePlus {
-                    // optimize I 1 +
-                    out_words[i] = Word::IPlusConst(1);
-                    out_words.remove(i + 1);
-                } else if let Word::Number(constant) = next {
-                    next = out_words.get(i + 2).unwrap();
-                    if next == &Word::Plus {
-                        // if it is `I <const> +`, then do IPlusConst optimization
-                        out_words[i] = Word::IPlusConst(*constant);
-                        out_words.remove(i + 1);
-                        out_words.remove(i + 1); //remove the extraneous operations
+            Word::EndCase => {
+                // every endof in this case skips straight past the endcase, which would drop the selector
+                loop {
+                    let j = open.pop().ok_or("Endcase without case")?;
+                    match words[j] {
+                        Word::EndOf(_) => words[j] = Word::EndOf(i + 1),
+                        Word::Case => break,
+                        _ => return Err("Endcase without case".to_string()),
                     }
                 }
             }
-            _ => {
-                // do nothing on default case
+            Word::Do => loops.push(Vec::new()),
+            Word::Leave(_) => loops.last_mut().ok_or("Leave without do")?.push(i),
+            Word::Loop | Word::PlusLoop | Word::PlusLoopConst(_) => {
+                for j in loops.pop().ok_or("Loop without do")? {
+                    words[j] = Word::Leave(i + 1);
+                }
             }
+            _ => {}
         }
-
-        i += 1;
     }
-}
 
-// inline_strings tries to concatanate Quotes as much as possible, to minimize instruction count
-fn inline_strings(
-    out_words: &mut Vec<Word>,
-    current_index: usize,
-    start: String,
-) -> Option<String> {
-    let i = current_index;
-    let mut concat = start;
-    let mut j = i + 1;
-    while let Some(next) = out_words.get(j) {
-        match next {
-            Word::Cr => {
-                concat += "\n";
-            }
-            Word::Quote(inner) => {
-                concat += inner;
-            }
-            _ => {
-                break;
-            }
-        }
-        j += 1;
+    if !open.is_empty() {
+        return Err("No closing else, then, endof or endcase".to_string());
     }
-    if j != i + 1 {
-        let diff = j - i - 1;
-        for _ in 0..diff {
-            out_words.remove(i + 1);
-        }
-        return Some(concat);
+    if !loops.is_empty() {
+        return Err("No closing loop".to_string());
     }
-    None
+
+    Ok(())
 }
 
+// callees bigger than this stay calls, a call is cheap next to copying that much code everywhere
+const MAX_INLINED_CALLEE: usize = 64;
+// and nothing more is inlined into a definition once it is this big
+const MAX_INLINED_BODY: usize = 512;
+
+// inlines the calls in words one level deep, where the cost model allows it
 pub(crate) fn inline_function(
     func_name: &String,
     words: &Vec<Word>,
-    defined_words: HashMap<String, DefinedWord, RandomState>,
+    state: &State,
+) -> (Vec<Word>, HashSet<String>) {
+    inline_calls(func_name, words, state, true)
+}
+
+fn inline_calls(
+    func_name: &String,
+    words: &Vec<Word>,
+    state: &State,
+    limit_size: bool,
 ) -> (Vec<Word>, HashSet<String>) {
+    let dictionary = &state.dictionary;
     let mut output: Vec<Word> = Vec::with_capacity(words.len());
     let mut depends: HashSet<String> = HashSet::new();
 
     for word in words {
-        match word {
-            // inline functions if already defined
-            Word::Word(raw_word) => {
-                let defined_word = defined_words.get(raw_word);
-                // don't inline recursion for now
-                if raw_word == func_name {
-                    output.push(word.clone())
-                } else if let Some(cmd) = defined_word {
-                    let command = cmd.clone();
-                    output.append(&mut (*command.words).clone());
-                    depends.insert(raw_word.clone());
-                } else {
-                    output.push(word.clone())
-                }
-            }
+        // inline functions if already defined
+        let raw_word = match word {
+            Word::Word(raw_word) => raw_word.as_str(),
+            Word::Call(symbol) => dictionary.symbol_name(*symbol),
             _ => {
                 output.push(word.clone());
+                continue;
+            }
+        };
+        // don't inline recursion for now
+        if raw_word == func_name {
+            output.push(word.clone());
+            continue;
+        }
+        match dictionary.find(raw_word).map(|e| &e.kind) {
+            // an exit has to return from the callee, not whatever it was inlined into
+            // and the prelude's words are inlined into callers of any size
+            Some(EntryKind::Colon(cmd))
+                if !cmd.words.contains(&Word::Exit)
+                    && (!limit_size
+                        || cmd.always_inline
+                        || cmd.words.len() <= MAX_INLINED_CALLEE
+                            && output.len() + cmd.words.len() <= MAX_INLINED_BODY) =>
+            {
+                output.extend(cmd.words.iter().cloned());
+                depends.insert(raw_word.to_string());
+            }
+            // names which always push the same thing can be folded into numbers
+            Some(
+                &EntryKind::Constant(val)
+                | &EntryKind::Variable(val)
+                | &EntryKind::TwoVariable(val)
+                | &EntryKind::Buffer(val)
+                | &EntryKind::Created(val),
+            ) => {
+                output.push(Word::Number(val));
+                depends.insert(raw_word.to_string());
+            }
+            Some(&EntryKind::TwoConstant(one, two)) => {
+                output.push(Word::Number(one));
+                output.push(Word::Number(two));
+                depends.insert(raw_word.to_string());
+            }
+            // the optimizer folds these into the address they're added to
+            Some(&EntryKind::Field(offset)) => {
+                output.push(Word::Number(offset));
+                output.push(Word::Plus);
+                depends.insert(raw_word.to_string());
             }
+            _ => output.push(word.clone()),
         }
     }
 
     //make sure and do an optimization pass
-    optimization_pass(&mut output);
+    optimization_pass(&mut output, &state.optimizer);
+
+    // inlining moves words around, so the offsets need to be worked out again
+    if resolve_control_flow(&mut output).is_err() {
+        return (words.clone(), HashSet::new());
+    }
 
     (output, depends)
 }
 
-// given the current index, if the word is the start of a printed thing, returns the next clear index
+// parses `{: args | uninitialized -- outputs :}` starting at current_index, adding the names to locals
+// returns the number of locals taken from the stack, the number of zeroed ones, and the next clear index
+fn parse_locals(
+    current_index: usize,
+    words: &[&str],
+    locals: &mut Vec<String>,
+) -> Result<(usize, usize, usize), String> {
+    let mut args = 0;
+    let mut uninitialized = 0;
+    let mut after_bar = false;
+    let mut in_outputs = false;
+
+    let mut i = current_index + 1;
+    while i < words.len() {
+        match words[i] {
+            ":}" => return Ok((args, uninitialized, i + 1)),
+            // the outputs are only documentation
+            _ if in_outputs => {}
+            "--" => in_outputs = true,
+            "|" => after_bar = true,
+            "" => {}
+            name => {
+                locals.push(name.to_string());
+                if after_bar {
+                    uninitialized += 1;
+                } else {
+                    args += 1;
+                }
+            }
+        }
+        i += 1;
+    }
+
+    Err("No closing :} for locals".to_string())
+}
+
+// both ." and s" take the following words verbatim up to a lone "
+fn is_quote_start(word: &str) -> bool {
+    word == ".\"" || word.eq_ignore_ascii_case("s\"")
+}
+
+// given the index of a [: returns the index of the matching ;]
+fn find_quotation_end(start: usize, words: &[&str]) -> Result<usize, String> {
+    let mut depth = 0;
+    let mut i = start;
+    while i < words.len() {
+        let quote_last_index = skip_quote(i, words);
+        if quote_last_index != i {
+            i = quote_last_index;
+            continue;
+        }
+        match words[i] {
+            "[:" => depth += 1,
+            ";]" if depth == 1 => return Ok(i),
+            ";]" => depth -= 1,
+            "\\" => break,
+            _ => {}
+        }
+        i += 1;
+    }
+    Err("Expected ;] to end the quotation".to_string())
+}
+
+// given the current index, if the word is the start of a quoted thing, returns the next clear index
 pub fn skip_quote(current_index: usize, words: &[&str]) -> usize {
-    if words[current_index] == ".\"" {
+    if is_quote_start(words[current_index]) {
         // now find the end, and print the whole thing
         let mut quote_index = current_index + 1;
         while quote_index < words.len() && *words.get(quote_index).unwrap() != "\"" {
@@ -489,16 +850,47 @@ pub fn normalize_line(str: String) -> String {
     output.join(" ")
 }
 
+// inlines until nothing changes, collecting everything that was inlined along the way
+pub(crate) fn inline_fully(
+    func_name: &String,
+    words: &[Word],
+    state: &State,
+) -> (Vec<Word>, HashSet<String>) {
+    let mut out = words.to_vec();
+    let mut depends = HashSet::new();
+    let mut previous_len = usize::MAX;
+    // recursive words grow forever, so stop at the same limit run_entry uses
+    let mut passes = 0;
+    while out.len() != previous_len && passes < 16 {
+        previous_len = out.len();
+        let (inlined, inlined_depends) = inline_calls(func_name, &out, state, false);
+        out = inlined;
+        depends.extend(inlined_depends);
+        passes += 1;
+    }
+    (out, depends)
+}
+
 pub(crate) fn break_inlining(func_name: String, state: &mut State) {
-    for (name, word) in state.defined_words.clone() {
-        // if it depends on the inlined word, then break the dependency (assuming not recursive call)
-        if word.depends_on.contains(&*func_name) && name != func_name {
-            let mut new = word.clone();
-            new.words = new.original_words.clone();
-            state.defined_words.insert(name.clone(), new);
-
-            //also break inlining for the functions that depend on the checked function recursively
-            break_inlining(name.clone(), state);
+    // a worklist rather than recursion, words inlined into each other can go deep
+    let mut broken = vec![func_name];
+    while let Some(name) = broken.pop() {
+        for index in state.dictionary.take_inlined_into(&name) {
+            let entry = state.dictionary.get(index).unwrap();
+            // if it depends on the inlined word, then break the dependency (assuming not recursive call)
+            if let EntryKind::Colon(word) = &entry.kind {
+                if word.depends_on.contains(&name) && entry.name != name {
+                    let mut new = word.clone();
+                    new.words = new.original_words.clone();
+                    // nothing is inlined anymore, which also stops mutually dependent words from looping here
+                    new.depends_on.clear();
+                    // and the effect was worked out from what it called
+                    new.effect = None;
+                    // the functions that depend on the checked function have to be broken too
+                    broken.push(entry.name.clone());
+                    state.dictionary.set_colon(index, new);
+                }
+            }
         }
     }
 }
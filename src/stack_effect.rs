@@ -0,0 +1,273 @@
+use crate::dictionary::{Dictionary, EntryKind};
+use crate::parsing::Word;
+use ahash::HashSet;
+
+// this file is responsible for working out how a definition changes the data stack without
+// running it, from the effects of the primitives and words it calls
+
+// how many cells a word may take from the stack, and how many it leaves in their place
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct StackEffect {
+    pub(crate) inputs: usize,
+    pub(crate) outputs: usize,
+    // how many cells it takes on every path through it, fewer and it is sure to underflow
+    pub(crate) certain_inputs: usize,
+}
+
+impl StackEffect {
+    fn new(inputs: usize, outputs: usize) -> StackEffect {
+        StackEffect {
+            inputs,
+            outputs,
+            certain_inputs: inputs,
+        }
+    }
+}
+
+// the effect of a word that always takes and leaves the same number of cells, none otherwise
+// words that only move control are handled by infer
+fn primitive_effect(word: &Word) -> Option<(usize, usize)> {
+    let effect = match word {
+        Word::Cr
+        | Word::UDotR
+        | Word::Quote(_)
+        | Word::Then
+        | Word::Case
+        | Word::Unloop
+        | Word::EndLocals => (0, 0),
+        Word::Number(_)
+        | Word::Depth
+        | Word::I
+        | Word::J
+        | Word::IPlusConst(_)
+        | Word::Here
+        | Word::Tick(_)
+        | Word::Quotation(_)
+        | Word::LocalFetch(_) => (0, 1),
+        Word::StringLiteral(_) | Word::PlacedString(..) => (0, 2),
+        Word::Dot
+        | Word::DotQuote(_)
+        | Word::Drop
+        | Word::Emit
+        | Word::Comma
+        | Word::EndCase
+        | Word::LocalStore(_) => (1, 0),
+        Word::At | Word::Cells | Word::OnePlus | Word::EqZero => (1, 1),
+        Word::Dup | Word::DupModConst(_) | Word::TwoAt => (1, 2),
+        Word::TwoDrop | Word::Exclamation | Word::Type => (2, 0),
+        Word::Plus | Word::Equal | Word::Greater | Word::Less | Word::Mod | Word::Mult => (2, 1),
+        Word::Nip => (2, 1),
+        Word::Swap => (2, 2),
+        Word::Over | Word::Tuck => (2, 3),
+        Word::TwoDup => (2, 4),
+        Word::TwoExclamation => (3, 0),
+        Word::MultDivide => (3, 1),
+        Word::Rot | Word::DoubleRot => (3, 3),
+        Word::TwoSwap => (4, 4),
+        Word::TwoOver => (4, 6),
+        Word::TwoRot => (6, 6),
+        Word::Locals(args, _) => (*args, 0),
+        _ => return None,
+    };
+    Some(effect)
+}
+
+// the effect of calling the dictionary entry at index, none if it can't be known
+pub(crate) fn entry_effect(dictionary: &Dictionary, index: usize) -> Option<StackEffect> {
+    match &dictionary.get(index)?.kind {
+        EntryKind::Colon(word) => word.effect,
+        EntryKind::Variable(_)
+        | EntryKind::Constant(_)
+        | EntryKind::Value(_)
+        | EntryKind::TwoVariable(_)
+        | EntryKind::Buffer(_)
+        | EntryKind::Created(_) => Some(StackEffect::new(0, 1)),
+        EntryKind::TwoConstant(..) | EntryKind::TwoValue(_) => Some(StackEffect::new(0, 2)),
+        EntryKind::Field(_) => Some(StackEffect::new(1, 1)),
+        EntryKind::Native(word) => {
+            primitive_effect(word).map(|(inputs, outputs)| StackEffect::new(inputs, outputs))
+        }
+        EntryKind::Host(word) => Some(StackEffect::new(word.inputs(), word.outputs())),
+    }
+}
+
+enum Call {
+    // its effect, and the cells it takes on every path
+    Known(StackEffect, i64),
+    Unknown,
+    // a recursive call with no effect assumed for it
+    Recursion,
+}
+
+// what calling the entry at index does, from inside the definition of this
+// a recursive call may not take everything it could on every path, so none are counted as certain
+fn call_effect(
+    dictionary: &Dictionary,
+    index: usize,
+    this: usize,
+    assumed: Option<StackEffect>,
+) -> Call {
+    if index == this {
+        return match assumed {
+            Some(effect) => Call::Known(effect, 0),
+            None => Call::Recursion,
+        };
+    }
+    match entry_effect(dictionary, index) {
+        Some(effect) => Call::Known(effect, effect.certain_inputs as i64),
+        None => Call::Unknown,
+    }
+}
+
+fn call(effect: StackEffect, certain: i64, i: usize, depth: i64) -> (i64, i64, Vec<(usize, i64)>) {
+    let leaves = depth - effect.inputs as i64 + effect.outputs as i64;
+    (effect.inputs as i64, certain, vec![(i + 1, leaves)])
+}
+
+fn branches_differ() -> String {
+    "leaves the stack at different depths depending on the branch taken".to_string()
+}
+
+// the effect of words (with their jump targets resolved), none if something in them can't be known
+// names whose meaning it relied on go in depends, redefining any of them makes it wrong
+// an error says the depth depends on the branch taken, so there's no single effect
+// this is the entry the words are the definition of, so calls to it are recursion
+pub(crate) fn infer(
+    words: &[Word],
+    dictionary: &Dictionary,
+    this: usize,
+    depends: &mut HashSet<String>,
+) -> Result<Option<StackEffect>, String> {
+    // the paths that don't recurse give the only effect it could have, which holds if assuming it
+    // for the recursive calls gives it back
+    let Some(effect) = infer_assuming(words, dictionary, this, None, depends)? else {
+        return Ok(None);
+    };
+    match infer_assuming(words, dictionary, this, Some(effect), depends)? {
+        Some(checked) if checked.inputs == effect.inputs && checked.outputs == effect.outputs => {
+            Ok(Some(checked))
+        }
+        _ => Ok(None),
+    }
+}
+
+// infer, with recursive calls having the effect assumed, or ending the path when there isn't one
+fn infer_assuming(
+    words: &[Word],
+    dictionary: &Dictionary,
+    this: usize,
+    assumed: Option<StackEffect>,
+    depends: &mut HashSet<String>,
+) -> Result<Option<StackEffect>, String> {
+    // where each loop word goes back to, just after its do
+    let mut loop_starts = vec![0; words.len()];
+    let mut open_loops = Vec::new();
+    for (i, word) in words.iter().enumerate() {
+        match word {
+            Word::Do => open_loops.push(i + 1),
+            Word::Loop | Word::PlusLoop | Word::PlusLoopConst(_) => match open_loops.pop() {
+                Some(start) => loop_starts[i] = start,
+                None => return Ok(None),
+            },
+            _ => {}
+        }
+    }
+
+    // depth on reaching each word relative to the start, and the most cells needed to get there
+    // on the path needing fewest, the end of the words is one past the last
+    let mut depths: Vec<Option<i64>> = vec![None; words.len() + 1];
+    let mut needed: Vec<i64> = vec![i64::MAX; words.len() + 1];
+    depths[0] = Some(0);
+    needed[0] = 0;
+    let mut inputs = 0;
+    let mut returned: Option<(i64, i64)> = None;
+    let mut work = vec![0];
+
+    while let Some(i) = work.pop() {
+        let depth = depths[i].unwrap();
+        // (cells it may take, cells it takes on every path, what it leaves the depth at and
+        // where it goes next)
+        let (takes, certain_takes, next): (i64, i64, Vec<(usize, i64)>) = match words.get(i) {
+            None | Some(Word::Exit) => {
+                let end = (depth, needed[i]);
+                match returned {
+                    Some((end_depth, _)) if end_depth != depth => return Err(branches_differ()),
+                    Some((_, end_needed)) if end_needed <= needed[i] => {}
+                    _ => returned = Some(end),
+                }
+                continue;
+            }
+            Some(Word::If(target) | Word::NotIf(target)) => {
+                (1, 1, vec![(i + 1, depth - 1), (*target, depth - 1)])
+            }
+            Some(Word::Else(target) | Word::EndOf(target) | Word::Leave(target)) => {
+                (0, 0, vec![(*target, depth)])
+            }
+            // a match drops the selector too
+            Some(Word::Of(target)) => (2, 2, vec![(i + 1, depth - 2), (*target, depth - 1)]),
+            Some(Word::Do) => (2, 2, vec![(i + 1, depth - 2)]),
+            Some(Word::Loop | Word::PlusLoopConst(_)) => {
+                (0, 0, vec![(i + 1, depth), (loop_starts[i], depth)])
+            }
+            Some(Word::PlusLoop) => (1, 1, vec![(i + 1, depth - 1), (loop_starts[i], depth - 1)]),
+            Some(Word::Word(name)) => {
+                let Some(index) = dictionary.find_index(name) else {
+                    return Ok(None);
+                };
+                depends.insert(name.clone());
+                match call_effect(dictionary, index, this, assumed) {
+                    Call::Known(effect, certain) => call(effect, certain, i, depth),
+                    Call::Unknown => return Ok(None),
+                    Call::Recursion => continue,
+                }
+            }
+            Some(Word::Call(symbol)) => {
+                let Some(index) = dictionary.resolve(*symbol) else {
+                    return Ok(None);
+                };
+                depends.insert(dictionary.symbol_name(*symbol).to_string());
+                match call_effect(dictionary, index, this, assumed) {
+                    Call::Known(effect, certain) => call(effect, certain, i, depth),
+                    Call::Unknown => return Ok(None),
+                    Call::Recursion => continue,
+                }
+            }
+            Some(word) => match primitive_effect(word) {
+                Some((takes, leaves)) => (
+                    takes as i64,
+                    takes as i64,
+                    vec![(i + 1, depth - takes as i64 + leaves as i64)],
+                ),
+                None => return Ok(None),
+            },
+        };
+
+        // below the starting depth is what has to be there to begin with
+        let needs = (certain_takes - depth).max(needed[i]);
+        inputs = inputs.max(takes - depth);
+        for (target, target_depth) in next {
+            match depths[target] {
+                // the way into a loop is always seen before the way back around it
+                Some(known) if known != target_depth && target == loop_starts[i] && i > target => {
+                    return Err("changes the stack depth each time around a loop".to_string());
+                }
+                Some(known) if known != target_depth => return Err(branches_differ()),
+                Some(_) if needed[target] <= needs => {}
+                _ => {
+                    depths[target] = Some(target_depth);
+                    needed[target] = needs;
+                    work.push(target);
+                }
+            }
+        }
+    }
+
+    let Some((end_depth, certain_inputs)) = returned else {
+        return Ok(None);
+    };
+    Ok(Some(StackEffect {
+        inputs: inputs as usize,
+        outputs: (end_depth + inputs) as usize,
+        certain_inputs: certain_inputs as usize,
+    }))
+}
@@ -0,0 +1,274 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// this file is responsible for the facility word set, reading keys from a stream separate from
+// the program source and moving the cursor around when the output is a terminal
+
+const ESCAPE: u8 = 0x1b;
+// how long to wait for the rest of an escape sequence before treating escape as its own key
+const ESCAPE_TIMEOUT: Duration = Duration::from_millis(25);
+// escape sequences longer than this are cut short, so ekey always fits in a cell
+const MAX_ESCAPE_SEQUENCE: usize = 8;
+
+// where keys are read from
+#[derive(Debug, Clone)]
+pub(crate) enum KeySource {
+    Stdin,
+    // the controlling terminal, for when stdin is the program itself
+    Tty,
+    // any reader handed over by an embedder
+    Reader(SharedReader),
+    // fixed input, for tests
+    #[cfg(test)]
+    Bytes(Vec<u8>),
+}
+
+// shared so the state can be cloned, the reader thread takes it the first time a key is read
+#[derive(Clone)]
+pub(crate) struct SharedReader(Arc<Mutex<Option<Box<dyn Read + Send>>>>);
+
+impl SharedReader {
+    pub(crate) fn new(reader: Box<dyn Read + Send>) -> SharedReader {
+        SharedReader(Arc::new(Mutex::new(Some(reader))))
+    }
+}
+
+impl std::fmt::Debug for SharedReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedReader")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Terminal {
+    source: KeySource,
+    // true when the output understands ansi escape sequences
+    ansi: bool,
+    // started on first use, so programs that never read keys leave stdin alone
+    keys: Option<Rc<RefCell<KeyReader>>>,
+}
+
+#[derive(Debug)]
+struct KeyReader {
+    // fed by a thread blocked on the input, so key? never has to wait
+    receiver: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+    // the stty settings to put back when done, if raw mode was turned on
+    saved_mode: Option<String>,
+}
+
+fn end_of_input_err() -> String {
+    "End of input".to_string()
+}
+
+// runs stty against the controlling terminal
+fn stty(args: &[&str]) -> Option<String> {
+    let tty = File::open("/dev/tty").ok()?;
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::from(tty))
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl KeyReader {
+    fn start(source: &KeySource) -> Result<KeyReader, String> {
+        let (sender, receiver) = channel();
+
+        let (input, is_terminal): (Box<dyn Read + Send>, bool) = match source {
+            KeySource::Stdin => (Box::new(std::io::stdin()), std::io::stdin().is_terminal()),
+            KeySource::Tty => match File::open("/dev/tty") {
+                Ok(tty) => (Box::new(tty), true),
+                Err(err) => return Err(format!("No terminal to read keys from: {err}")),
+            },
+            KeySource::Reader(reader) => match reader.0.lock().ok().and_then(|mut r| r.take()) {
+                Some(reader) => (reader, false),
+                None => return Err("Key input is already in use".to_string()),
+            },
+            #[cfg(test)]
+            KeySource::Bytes(bytes) => {
+                // no thread needed, everything is already here
+                return Ok(KeyReader {
+                    receiver,
+                    pending: bytes.iter().copied().collect(),
+                    saved_mode: None,
+                });
+            }
+        };
+
+        // keys should arrive as they're pressed, not a line at a time, and not be echoed
+        let saved_mode = if is_terminal {
+            stty(&["-g"]).filter(|_| stty(&["-icanon", "-echo", "min", "1"]).is_some())
+        } else {
+            None
+        };
+
+        // whole reads are sent at once, so key? sees everything that arrived together
+        let mut input = input;
+        std::thread::spawn(move || {
+            let mut buffer = [0; 256];
+            while let Ok(len @ 1..) = input.read(&mut buffer) {
+                if sender.send(buffer[..len].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(KeyReader {
+            receiver,
+            pending: VecDeque::new(),
+            saved_mode,
+        })
+    }
+
+    fn key(&mut self) -> Result<u8, String> {
+        while self.pending.is_empty() {
+            let bytes = self.receiver.recv().map_err(|_| end_of_input_err())?;
+            self.pending.extend(bytes);
+        }
+        Ok(self.pending.pop_front().unwrap())
+    }
+
+    fn key_ready(&mut self) -> bool {
+        while self.pending.is_empty() {
+            match self.receiver.try_recv() {
+                Ok(bytes) => self.pending.extend(bytes),
+                Err(TryRecvError::Empty | TryRecvError::Disconnected) => return false,
+            }
+        }
+        true
+    }
+
+    // the next byte if it arrives soon, used to tell escape sequences from a lone escape
+    fn key_soon(&mut self) -> Option<u8> {
+        while self.pending.is_empty() {
+            let bytes = self.receiver.recv_timeout(ESCAPE_TIMEOUT).ok()?;
+            self.pending.extend(bytes);
+        }
+        self.pending.pop_front()
+    }
+}
+
+impl Drop for KeyReader {
+    fn drop(&mut self) {
+        if let Some(mode) = &self.saved_mode {
+            stty(&[mode]);
+        }
+    }
+}
+
+impl Terminal {
+    pub(crate) fn new(source: KeySource, ansi: bool) -> Terminal {
+        Terminal {
+            source,
+            ansi,
+            keys: None,
+        }
+    }
+
+    pub(crate) fn set_ansi(&mut self, ansi: bool) {
+        self.ansi = ansi;
+    }
+
+    fn keys(&mut self) -> Result<std::cell::RefMut<'_, KeyReader>, String> {
+        if self.keys.is_none() {
+            self.keys = Some(Rc::new(RefCell::new(KeyReader::start(&self.source)?)));
+        }
+        Ok(self.keys.as_ref().unwrap().borrow_mut())
+    }
+
+    pub(crate) fn key(&mut self) -> Result<u8, String> {
+        self.keys()?.key()
+    }
+
+    pub(crate) fn key_ready(&mut self) -> Result<bool, String> {
+        Ok(self.keys()?.key_ready())
+    }
+
+    // like key, but a whole escape sequence (like an arrow key) comes back as one number,
+    // its bytes in order from most to least significant
+    pub(crate) fn ekey(&mut self) -> Result<i64, String> {
+        let mut keys = self.keys()?;
+        let first = keys.key()?;
+        if first != ESCAPE {
+            return Ok(first as i64);
+        }
+
+        let mut event = first as i64;
+        let mut len = 1;
+        while len < MAX_ESCAPE_SEQUENCE {
+            let Some(byte) = keys.key_soon() else { break };
+            event = (event << 8) | byte as i64;
+            len += 1;
+            // [ and O start a sequence, anything else from @ to ~ ends one
+            let starts_sequence = len == 2 && (byte == b'[' || byte == b'O');
+            if !starts_sequence && (len == 2 || (0x40..=0x7e).contains(&byte)) {
+                break;
+            }
+        }
+        Ok(event)
+    }
+
+    // reads up to a line terminator, keeping at most `max` bytes
+    // in raw mode the terminal doesn't echo, so the line is echoed here as it is typed
+    pub(crate) fn accept(&mut self, max: usize, output: &mut dyn Write) -> Result<Vec<u8>, String> {
+        let mut keys = self.keys()?;
+        let echo = keys.saved_mode.is_some();
+        let mut line = Vec::with_capacity(max);
+        // whatever was read before the input ended still counts
+        while let Ok(byte) = keys.key() {
+            match byte {
+                b'\n' | b'\r' => break,
+                // backspace and delete
+                0x08 | 0x7f => {
+                    let erased = line.pop().is_some();
+                    if erased && echo {
+                        output
+                            .write_all(b"\x08 \x08")
+                            .expect("Could not write output");
+                    }
+                }
+                _ if line.len() < max => {
+                    line.push(byte);
+                    if echo {
+                        output.write_all(&[byte]).expect("Could not write output");
+                    }
+                }
+                _ => {}
+            }
+        }
+        if echo {
+            output.write_all(b"\n").expect("Could not write output");
+        }
+        Ok(line)
+    }
+
+    // columns and rows count from 0, from the top left
+    pub(crate) fn at_xy(&self, column: i64, row: i64, output: &mut dyn Write) {
+        if self.ansi {
+            write!(output, "\x1b[{};{}H", row + 1, column + 1).expect("Could not write output");
+        }
+    }
+
+    pub(crate) fn page(&self, output: &mut dyn Write) {
+        if self.ansi {
+            output
+                .write_all(b"\x1b[2J\x1b[H")
+                .expect("Could not write output");
+        } else {
+            output.write_all(b"\n").expect("Could not write output");
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use crate::blocks::BlockStore;
+use crate::clock::Clock;
+use crate::dictionary::{EntryKind, SourceLocation};
+use crate::host::{HostContext, HostWord};
+use crate::optimizer;
+use crate::terminal::{KeySource, SharedReader, Terminal};
+use crate::{define, interpret_line, parsing, prelude, run_entry, try_output_cpp, Error, State};
+use std::io::{stdout, BufWriter, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// this file is responsible for the embedding api, everything a host program needs to run forth
+// without knowing how the interpreter is put together
+
+/// A Forth interpreter with its own stack, dictionary and memory.
+pub struct Forth {
+    stack: Vec<i64>,
+    state: State,
+    output: Box<dyn Write>,
+    // so an interpreter built to check this one against can load it too
+    prelude_loaded: bool,
+    // the unoptimized interpreter lines are checked against, when verifying optimizations
+    reference: Option<Box<Reference>>,
+}
+
+struct Reference {
+    stack: Vec<i64>,
+    state: State,
+    // its own copy of the block file, so blocks aren't written twice to the real one
+    block_file: PathBuf,
+}
+
+impl Drop for Reference {
+    fn drop(&mut self) {
+        _ = std::fs::remove_file(&self.block_file);
+    }
+}
+
+// tells apart the block file copies of interpreters verified at the same time
+static REFERENCE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+impl Default for Forth {
+    fn default() -> Forth {
+        Forth::new()
+    }
+}
+
+impl Forth {
+    /// An interpreter with the prelude loaded, writing to stdout and reading keys from stdin.
+    pub fn new() -> Forth {
+        let mut forth = Forth::without_prelude();
+        forth
+            .load_prelude()
+            .expect("The prelude should always load");
+        forth
+    }
+
+    /// An interpreter with only the built in words.
+    pub fn without_prelude() -> Forth {
+        let mut state = State::new();
+        state.terminal = Terminal::new(KeySource::Stdin, stdout().is_terminal());
+        Forth {
+            stack: Vec::with_capacity(10),
+            state,
+            output: Box::new(BufWriter::new(stdout())),
+            prelude_loaded: false,
+            reference: None,
+        }
+    }
+
+    /// Loads the prelude into an interpreter made without one, parsed with the optimization
+    /// settings made so far.
+    pub fn load_prelude(&mut self) -> Result<(), Error> {
+        prelude(&mut self.stack, &mut self.state)?;
+        self.prelude_loaded = true;
+        Ok(())
+    }
+
+    /// Sends everything the program prints to `output` instead of stdout.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        // anything already printed belongs to the old output
+        _ = self.output.flush();
+        self.output = output;
+        self.state.terminal.set_ansi(false);
+    }
+
+    /// Reads the keys for KEY, EKEY and ACCEPT from `input` instead of stdin.
+    pub fn set_input(&mut self, input: Box<dyn Read + Send>) {
+        self.state.terminal = Terminal::new(KeySource::Reader(SharedReader::new(input)), false);
+    }
+
+    /// Reads keys from the controlling terminal, for when stdin holds the program.
+    pub fn read_keys_from_terminal(&mut self) {
+        self.state.terminal = Terminal::new(KeySource::Tty, stdout().is_terminal());
+    }
+
+    /// Makes use after free and double free errors instead of undefined results.
+    pub fn set_debug_memory(&mut self, debug: bool) {
+        self.state.memory.set_debug(debug);
+    }
+
+    /// The file BLOCK, LOAD and friends read and write.
+    pub fn set_block_file(&mut self, path: impl Into<PathBuf>) {
+        self.state.blocks = BlockStore::new(path.into());
+    }
+
+    /// A clock that starts at the epoch and only moves when MS is called.
+    pub fn use_virtual_clock(&mut self) {
+        self.state.clock = Clock::new_virtual();
+    }
+
+    /// How deep colon definitions can call each other before it's an error.
+    pub fn set_max_return_depth(&mut self, depth: usize) {
+        self.state.max_return_depth = depth;
+    }
+
+    /// Every peephole optimization rule and pass, one `name: pattern -> replacement` line each.
+    pub fn optimization_rules() -> Vec<String> {
+        optimizer::describe_rules()
+    }
+
+    /// Turns the peephole rule `name` on or off for code this interpreter parses from now on.
+    /// The prelude is already parsed by then unless it is loaded with [`Forth::load_prelude`].
+    pub fn set_rule_enabled(&mut self, name: &str, enabled: bool) -> Result<(), Error> {
+        Ok(self.state.optimizer.set_rule_enabled(name, enabled)?)
+    }
+
+    /// Turns peephole rules and inlining on or off for code this interpreter parses and runs
+    /// from now on. The prelude is already parsed by then unless it is loaded with
+    /// [`Forth::load_prelude`].
+    pub fn set_optimizing(&mut self, optimizing: bool) {
+        self.state.optimizer.optimizing = optimizing;
+    }
+
+    /// Runs every line from now on twice, optimized and on a separate interpreter that isn't,
+    /// and fails with a report on the first line where the output, stack or success differs,
+    /// after which lines are no longer verified. A line failing both ways counts as the same,
+    /// since optimized code can fail before what the unoptimized code prints on the way.
+    ///
+    /// Call it after the other settings and before evaluating anything, since the other
+    /// interpreter starts from just the prelude and host words. It reads no keys and writes its
+    /// blocks to a copy of the block file, so lines reading keys or the real clock will differ.
+    pub fn verify_optimizations(&mut self) -> Result<(), Error> {
+        let mut state = State::new();
+        state.optimizer.optimizing = false;
+        state.max_return_depth = self.state.max_return_depth;
+        state.clock = self.state.clock.clone();
+        state.memory.set_debug(self.state.memory.debug());
+        state.terminal = Terminal::new(
+            KeySource::Reader(SharedReader::new(Box::new(std::io::empty()))),
+            false,
+        );
+
+        let count = REFERENCE_COUNT.fetch_add(1, Ordering::Relaxed);
+        let block_file =
+            std::env::temp_dir().join(format!("forth-verify-{}-{count}.blk", std::process::id()));
+        if self.state.blocks.path().exists() {
+            std::fs::copy(self.state.blocks.path(), &block_file)?;
+        }
+        state.blocks = BlockStore::new(block_file.clone());
+
+        let mut reference = Reference {
+            stack: self.stack.clone(),
+            state,
+            block_file,
+        };
+        if self.prelude_loaded {
+            prelude(&mut reference.stack, &mut reference.state)?;
+        }
+        for (_, entry) in self.state.dictionary.entries() {
+            if let EntryKind::Host(word) = &entry.kind {
+                if !entry.hidden {
+                    define(
+                        &mut reference.state,
+                        &entry.name,
+                        EntryKind::Host(word.clone()),
+                    );
+                }
+            }
+        }
+        self.reference = Some(Box::new(reference));
+        Ok(())
+    }
+
+    /// Interprets `source`, which may hold several lines, as the main input.
+    pub fn eval(&mut self, source: &str) -> Result<(), Error> {
+        for (line_number, line) in source.lines().enumerate() {
+            if !line.is_empty() {
+                self.eval_line(line, "eval", line_number + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Interprets one line of the main input, `line_number` counting from 1.
+    pub fn eval_line(
+        &mut self,
+        line: &str,
+        source_name: &str,
+        line_number: usize,
+    ) -> Result<(), Error> {
+        self.state.location = SourceLocation::new(source_name, line_number);
+        if self.reference.is_some() {
+            return self.eval_line_verified(line);
+        }
+        let result = interpret_line(
+            &mut self.stack,
+            &mut self.state,
+            line.to_string(),
+            &mut *self.output,
+        );
+        self.output.flush()?;
+        result.map(|_| ())
+    }
+
+    fn eval_line_verified(&mut self, line: &str) -> Result<(), Error> {
+        let reference = self.reference.as_mut().unwrap();
+        reference.state.location = self.state.location.clone();
+        let mut expected_output = Vec::new();
+        let expected = interpret_line(
+            &mut reference.stack,
+            &mut reference.state,
+            line.to_string(),
+            &mut expected_output,
+        );
+
+        optimizer::start_recording();
+        let mut output = Vec::new();
+        let result = interpret_line(
+            &mut self.stack,
+            &mut self.state,
+            line.to_string(),
+            &mut output,
+        );
+        let rewrites = optimizer::stop_recording();
+        self.output.write_all(&output)?;
+        self.output.flush()?;
+
+        // error messages can differ just by naming words that were inlined away, so only
+        // whether it failed is compared
+        // optimized code can fail before running what would print or push before the failure,
+        // so a line failing both ways differs in nothing else, and the two go on from one stack
+        let difference = if result.is_err() && expected.is_err() {
+            reference.stack = self.stack.clone();
+            None
+        } else if result.is_ok() != expected.is_ok() {
+            Some(format!(
+                "{} optimized but {} unoptimized",
+                describe_result(&result),
+                describe_result(&expected)
+            ))
+        } else if output != expected_output {
+            Some(format!(
+                "printed {:?} optimized but {:?} unoptimized",
+                String::from_utf8_lossy(&output),
+                String::from_utf8_lossy(&expected_output)
+            ))
+        } else if self.stack != reference.stack {
+            Some(format!(
+                "left {:?} optimized but {:?} unoptimized",
+                self.stack, reference.stack
+            ))
+        } else {
+            None
+        };
+
+        if let Some(difference) = difference {
+            // the two can't be brought back in step without sharing state, so stop checking
+            self.reference = None;
+            let rewrites = if rewrites.is_empty() {
+                "none".to_string()
+            } else {
+                rewrites.join(", ")
+            };
+            return Err(Error::from(format!(
+                "Optimization changed the result of {}: {difference}, rewrites made: {rewrites}",
+                self.state.location
+            )));
+        }
+        result.map(|_| ())
+    }
+
+    /// C++ for the word named on `line` if it is a single defined word, none otherwise
+    /// or while [if] is skipping source.
+    pub fn cpp_for_line(&self, line: &str) -> Result<Option<String>, Error> {
+        if self.state.conditional_skip > 0 {
+            return Ok(None);
+        }
+        let parsed_line =
+            parsing::parse_line(parsing::normalize_line(line.to_string()), &self.state)?;
+        Ok(try_output_cpp(&parsed_line, &self.state))
+    }
+
+    /// Defines `name` as a colon definition with `body` as its source.
+    pub fn define(&mut self, name: &str, body: &str) -> Result<(), Error> {
+        valid_name(name)?;
+        self.eval(&format!(": {name} {body} ;"))
+    }
+
+    /// Makes `name` run `word`, which takes `inputs` cells from the stack and leaves `outputs`.
+    /// Calls fail if the word leaves the stack any other depth.
+    pub fn register(
+        &mut self,
+        name: &str,
+        inputs: usize,
+        outputs: usize,
+        word: impl Fn(&mut HostContext<'_>) -> Result<(), Error> + 'static,
+    ) -> Result<(), Error> {
+        let name = valid_name(name)?;
+        let outer_location =
+            std::mem::replace(&mut self.state.location, SourceLocation::new("host", 0));
+        let kind = EntryKind::Host(HostWord::new(&name, inputs, outputs, word));
+        if let Some(reference) = &mut self.reference {
+            define(&mut reference.state, &name, kind.clone());
+        }
+        define(&mut self.state, &name, kind);
+        self.state.location = outer_location;
+        Ok(())
+    }
+
+    /// Runs the word `name` against the current stack.
+    pub fn call_word(&mut self, name: &str) -> Result<(), Error> {
+        let name = name.to_lowercase();
+        let Some(entry_index) = self.state.dictionary.find_index(&name) else {
+            return Err(Error::from(format!("Unknown word {name}")));
+        };
+        let result = run_entry(
+            &mut self.stack,
+            &mut self.state,
+            0,
+            entry_index,
+            &mut *self.output,
+        );
+        self.output.flush()?;
+        Ok(result?)
+    }
+
+    /// Takes the warnings about definitions made since it was last called, like one leaving
+    /// the stack at different depths depending on the branch taken.
+    pub fn take_warnings(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.state.warnings)
+    }
+
+    /// Pushes `value` onto the data stack.
+    pub fn push(&mut self, value: i64) {
+        self.stack.push(value);
+    }
+
+    /// Pops the top of the data stack, none if it is empty.
+    pub fn pop(&mut self) -> Option<i64> {
+        self.stack.pop()
+    }
+
+    /// The data stack, top last.
+    pub fn stack(&self) -> &[i64] {
+        &self.stack
+    }
+}
+
+fn describe_result(result: &Result<String, Error>) -> String {
+    match result {
+        Ok(_) => "succeeded".to_string(),
+        Err(e) => format!("failed with {e:?}"),
+    }
+}
+
+// names are single tokens, looked up in lower case like everything else
+fn valid_name(name: &str) -> Result<String, Error> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return Err(Error::from(format!("Invalid word name {name:?}")));
+    }
+    Ok(name.to_lowercase())
+}